@@ -0,0 +1,48 @@
+use hotpatch::*;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+
+/// async functions work just like normal ones, and are still awaited
+#[patchable]
+async fn foo(a: i32) -> String {
+    format!("I am from source foo. I have {} as an arg.", a)
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", foo(1).await);
+    foo.hotpatch_fn(|a: i32| -> BoxFuture<String> {
+        Box::pin(async move { format!("I am an anonymous future. I have {} as an arg.", a) })
+    })?;
+    println!("{}", foo(2).await);
+    foo.hotpatch_lib("target/debug/libasync_obj.so")?;
+    println!("{}", foo(3).await);
+    Ok(())
+}
+
+// A tiny executor, so this example doesn't need a runtime
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = Box::pin(fut);
+    let waker = Arc::new(ThreadWaker(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(res) => return res,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    block_on(run())
+}
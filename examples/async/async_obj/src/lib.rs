@@ -0,0 +1,7 @@
+use hotpatch::patch;
+
+#[patch]
+/// The exported definition boxes the future, just like the patchable one
+pub async fn foo(a: i32) -> String {
+    format!("I am from patched foo. I have {} as an arg.", a)
+}
@@ -0,0 +1,22 @@
+use hotpatch::*;
+
+use std::str::FromStr;
+
+/// Generic functions need to know which types they'll be used with.
+/// Each instantiation gets its own Patchable in the `parse` module.
+#[patchable(instantiate(T = u32, T = String))]
+fn parse<T: FromStr + Default>(s: &str) -> T {
+    s.parse().unwrap_or_default()
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("{} {:?}", parse::<u32>("12"), parse::<String>("twelve"));
+
+    parse::u32.hotpatch_fn(|s: &str| s.len() as u32)?;
+    println!("{} {:?}", parse::<u32>("12"), parse::<String>("twelve"));
+
+    parse::u32.hotpatch_lib("target/debug/libgenerics_obj.so")?;
+    parse::string.hotpatch_lib("target/debug/libgenerics_obj.so")?;
+    println!("{} {:?}", parse::<u32>("12"), parse::<String>("twelve"));
+    Ok(())
+}
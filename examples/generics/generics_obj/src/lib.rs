@@ -0,0 +1,9 @@
+use hotpatch::patch;
+
+use std::str::FromStr;
+
+/// Every instantiation is exported seperately
+#[patch(instantiate(T = u32, T = String))]
+pub fn parse<T: FromStr + Default>(s: &str) -> T {
+    format!("{}{}", s, s).parse().unwrap_or_default()
+}
@@ -17,6 +17,16 @@ fn foo() {
     println!("I am from source foo.");
 }
 
+#[patchable]
+fn double(a: i32) -> i32 {
+    a * 2
+}
+
+#[patchable]
+fn half(a: i32) -> i32 {
+    a / 2
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     foo(); // prints "I am from source"
     foo.hotpatch_lib("target/debug/libhw_obj.so")?;
@@ -26,5 +36,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     bar(1);
     bar.hotpatch_lib("target/debug/libhw_obj.so")?;
     bar(2);
+
+    // patch both at once, then undo both at once
+    foo.restore_default()?;
+    bar.restore_default()?;
+    let mut set = PatchSet::new("target/debug/libhw_obj.so")?;
+    set.add(&foo)?.add(&bar)?;
+    let patches = set.commit()?;
+    foo();
+    bar(3);
+    patches.rollback()?;
+    foo();
+    bar(4);
+
+    // a wrapper from a library still runs the definition it replaced
+    double.hotpatch_lib("target/debug/libhw_obj.so")?;
+    println!("double(2) is {}", double(2));
+    // loading it again replaces that wrapper rather than adding another
+    double.hotpatch_lib("target/debug/libhw_obj.so")?;
+    println!("double(3) is {}", double(3));
+
+    // and any patch can fall back on the source definition
+    half.hotpatch_lib("target/debug/libhw_obj.so")?;
+    println!("half(8) is {}, half(-8) is {}", half(8), half(-8));
     Ok(())
 }
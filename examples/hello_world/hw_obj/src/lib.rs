@@ -16,3 +16,20 @@ mod a {
         );
     }
 }
+
+#[patch(wrap)]
+/// Patches can also wrap the definition they replace, which is passed in first
+pub fn double(prev: &dyn Fn(i32) -> i32, a: i32) -> i32 {
+    println!("I am from a patch wrapping double. I have {} as an arg.", a);
+    prev(a)
+}
+
+#[patch]
+/// Patches can call the definition compiled into the host with original!()
+pub fn half(a: i32) -> i32 {
+    if a < 0 {
+        println!("I am from patched half. I handle negative numbers myself.");
+        return 0;
+    }
+    hotpatch::original!()(a)
+}
@@ -10,6 +10,12 @@ fn foo(_: i32) {
 #[patch]
 fn tmp(_: i32) {}
 
+/// I put myself back if a patch panics
+#[patchable(catch_panic(revert))]
+fn baz() -> &'static str {
+    "I am Baz"
+}
+
 fn bar(_: i32) {
     println!("Foo Becomes Bar");
 }
@@ -21,5 +27,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let a = 5;
     foo.hotpatch_fn(move |_: i32| println!("Foo becomes anonymous {}", a))?;
     foo(1);
+    foo.rollback()?; // back to bar
+    foo(1);
+    let logged = foo.wrap(|prev, a| {
+        println!("Bar is about to be called with {}", a);
+        prev(a)
+    })?;
+    foo(1);
+    foo.remove_wrapper(logged)?; // just bar again
+    foo(1);
+
+    set_panic_hook(|p| println!("{} panicked: {} (reverted: {})", p.path, p.message, p.reverted));
+    baz.hotpatch_fn(|| -> &'static str { panic!("Baz has a bug") })?;
+    let _ = std::panic::catch_unwind(|| println!("{}", baz())); // the panic still reaches the caller
+    println!("{}", baz()); // but baz is back to normal
     Ok(())
 }
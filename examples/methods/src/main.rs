@@ -1,6 +1,8 @@
 use hotpatch::*;
 
 /// This is a struct
+/// Deriving HotpatchAbi makes sure patches agree on its layout
+#[derive(HotpatchAbi)]
 struct Foo {
     description: &'static str,
 }
@@ -13,6 +15,22 @@ impl Foo {
             description: "This object was created with the original definition",
         }
     }
+    /// Methods work too. The receiver becomes the first arguement of the Patchable
+    fn describe(&self) -> String {
+        format!("Original description: {}", self.description)
+    }
+    /// Along with mutable receivers
+    fn rename(&mut self, description: &'static str) {
+        self.description = description;
+    }
+}
+
+/// Trait implementations can be patchable too
+#[patchable]
+impl std::fmt::Display for Foo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Foo displayed by the original definition")
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -26,7 +44,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Second description: {}", f.description);
 
     Foo::new.hotpatch_lib("target/debug/libmethods_obj.so")?;
-    let f = Foo::new();
+    let mut f = Foo::new();
     println!("Third description: {}", f.description);
+
+    // methods keep their call syntax; the Patchable is found at `Self::<name>_patchable`
+    println!("{}", f.describe());
+    Foo::describe_patchable.hotpatch_lib("target/debug/libmethods_obj.so")?;
+    println!("{}", f.describe());
+
+    f.rename("Renamed with the original definition");
+    println!("{}", f.describe());
+    Foo::rename_patchable.hotpatch_fn(|f: &mut Foo, _: &'static str| {
+        f.description = "Renamed with an anonymous definition";
+    })?;
+    f.rename("This will be ignored");
+    println!("{}", f.describe());
+
+    // trait methods are reached through dynamic dispatch as well
+    let d: &dyn std::fmt::Display = &f;
+    println!("{}", d);
+    Foo::std_fmt_display_fmt_patchable.hotpatch_lib("target/debug/libmethods_obj.so")?;
+    println!("{}", d);
+
+    // every patchable item can be listed, along with what it's currently running
+    for item in hotpatch::registry().iter() {
+        println!("{} {}: {}", item.path(), item.sig(), item.state());
+    }
     Ok(())
 }
@@ -1,8 +1,8 @@
 use hotpatch::*;
 
-/// There is a system of trust here
-/// Foo is assumed to be the same struct everywhere
-/// This may be possible to lock down even more with typeid, but that's WIP upstream
+/// Foo has to have the same layout here as in the binary
+/// HotpatchAbi fingerprints it, so a library built with a different Foo is rejected
+#[derive(HotpatchAbi)]
 pub struct Foo {
     pub description: &'static str,
 }
@@ -10,9 +10,22 @@ pub struct Foo {
 #[patch]
 impl Foo {
     /// remember, #[patch] is top-level
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             description: "This object was created in a dynamic library",
         }
     }
+    /// methods are exported with the receiver as the first arguement
+    pub fn describe(&self) -> String {
+        format!("Patched description: {}", self.description)
+    }
+}
+
+#[patch]
+impl std::fmt::Display for Foo {
+    /// trait implementations are exported by trait and type
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Foo displayed by a dynamic library")
+    }
 }
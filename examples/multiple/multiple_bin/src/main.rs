@@ -13,5 +13,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     foo();
     foo.restore_default()?;
     foo();
+
+    // or patch everything a library exports at once
+    let report = hotpatch::apply_library("target/debug/libmultiple_obj1.so")?;
+    println!("Applied {:?}", report.applied);
+    foo();
     Ok(())
 }
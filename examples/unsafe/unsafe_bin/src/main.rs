@@ -1,4 +1,4 @@
-#![feature(main)]
+#![cfg_attr(not(test), no_main)]
 
 use hotpatch::*;
 
@@ -21,12 +21,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 fn foo() -> Result<(), Box<dyn std::error::Error>> {
     println!("Hello from foo");
-    unsafe { main.force_hotpatch_fn(bar) }
+    unsafe { main.force_hotpatch_fn(bar)? };
+    Ok(())
 }
 
 fn bar() -> Result<(), Box<dyn std::error::Error>> {
     println!("Hello from bar");
-    unsafe { main.force_hotpatch_fn(baz) }
+    unsafe { main.force_hotpatch_fn(baz)? };
+    Ok(())
 }
 
 fn baz() -> Result<(), Box<dyn std::error::Error>> {
@@ -34,5 +36,6 @@ fn baz() -> Result<(), Box<dyn std::error::Error>> {
     unsafe { main.force_hotpatch_fn(|| {
 	println!("Hello from a closure");
 	Ok(())
-    }) }
+    })? };
+    Ok(())
 }
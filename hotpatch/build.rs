@@ -0,0 +1,36 @@
+// Records how this copy of hotpatch was built, so that patch libraries built
+// differently from the binary loading them can be rejected. See src/stamp.rs.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc = Command::new(rustc)
+        .arg("-V")
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|version| version.trim().to_owned())
+        .unwrap_or_default();
+    let profile = match env::var_os("CARGO_CFG_DEBUG_ASSERTIONS") {
+        Some(_) => "debug",
+        None => "release",
+    };
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase()))
+        .collect();
+    features.sort();
+
+    let stamp = [
+        ("rustc", rustc),
+        ("target", env::var("TARGET").unwrap_or_default()),
+        ("hotpatch", env::var("CARGO_PKG_VERSION").unwrap_or_default()),
+        ("panic", env::var("CARGO_CFG_PANIC").unwrap_or_default()),
+        ("profile", profile.to_owned()),
+        ("features", features.join(",")),
+    ];
+    let stamp: Vec<String> = stamp.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    println!("cargo:rustc-env=HOTPATCH_BUILD_STAMP={}", stamp.join(";"));
+    println!("cargo:rerun-if-changed=build.rs");
+}
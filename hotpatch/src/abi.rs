@@ -0,0 +1,298 @@
+use std::marker::PhantomData;
+
+use crate::BoxFuture;
+
+/// A fingerprint of a type's memory layout, compared when hotpatching.
+///
+/// Signatures are compared as text, so a `Foo` whose fields changed between the
+/// binary and the library would otherwise pass the check and be read with the wrong
+/// layout. For every arguement and return type that implements `HotpatchAbi`,
+/// [`#[patchable]`](crate::patchable) and [`#[patch]`](crate::patch) also record its
+/// fingerprint, and a library is rejected with
+/// [`HotpatchError::LayoutMismatch`](crate::HotpatchError::LayoutMismatch) if they differ.
+/// Types that don't implement it are only checked by name.
+///
+/// Implemented for primitives and common `std` types. Derive it for your own:
+/// ```
+/// # use hotpatch::HotpatchAbi;
+/// #[derive(HotpatchAbi)]
+/// pub struct Foo {
+///     pub description: &'static str,
+/// }
+/// ```
+/// The derived fingerprint covers the type's name, size and alignment, and the name,
+/// offset and fingerprint of each field, so every field type must implement
+/// `HotpatchAbi` too. Enums cover each variant and its fields instead of offsets.
+pub trait HotpatchAbi {
+    const FINGERPRINT: u64;
+}
+
+/// Builds a [`HotpatchAbi::FINGERPRINT`](HotpatchAbi::FINGERPRINT) at compile time.
+/// Internal use only.
+#[doc(hidden)]
+pub struct Fingerprint(u64);
+
+// FNV-1a, the same hash the macros name exports with
+#[doc(hidden)]
+impl Fingerprint {
+    pub const fn new(name: &str) -> Self {
+        Fingerprint(0xcbf2_9ce4_8422_2325).str(name)
+    }
+    const fn bytes(mut self, bytes: &[u8]) -> Self {
+        let mut i = 0;
+        while i < bytes.len() {
+            self.0 ^= bytes[i] as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+            i += 1;
+        }
+        self
+    }
+    // terminated, so that "ab", "c" and "a", "bc" differ
+    pub const fn str(self, s: &str) -> Self {
+        self.bytes(s.as_bytes()).bytes(&[0])
+    }
+    pub const fn u64(self, n: u64) -> Self {
+        self.bytes(&n.to_le_bytes())
+    }
+    pub const fn usize(self, n: usize) -> Self {
+        self.u64(n as u64)
+    }
+    pub const fn layout<T>(self) -> Self {
+        self.usize(std::mem::size_of::<T>())
+            .usize(std::mem::align_of::<T>())
+    }
+    pub const fn field(self, name: &str, offset: usize, fingerprint: u64) -> Self {
+        self.str(name).usize(offset).u64(fingerprint)
+    }
+    // enum fields have no stable offset, so they're hashed in order within their variant
+    pub const fn variant(self, name: &str) -> Self {
+        self.str(name)
+    }
+    pub const fn member(self, name: &str, fingerprint: u64) -> Self {
+        self.str(name).u64(fingerprint)
+    }
+    pub const fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+/// Fingerprint of a whole signature, from the fingerprints of its arguements and
+/// then its return type, as given by [`Probe`](Probe). Internal use only.
+#[doc(hidden)]
+pub fn signature(fingerprints: &[u64]) -> u64 {
+    fingerprints
+        .iter()
+        .fold(Fingerprint::new("fn"), |f, n| f.u64(*n))
+        .finish()
+}
+
+/// Finds the fingerprint of a type, or 0 if it doesn't implement [`HotpatchAbi`](HotpatchAbi).
+/// Call as `(&Probe::<T>::new()).fingerprint()` with both [`AbiKnown`](AbiKnown) and
+/// [`AbiUnknown`](AbiUnknown) in scope. Internal use only.
+#[doc(hidden)]
+pub struct Probe<T: ?Sized>(PhantomData<T>);
+
+#[doc(hidden)]
+#[allow(clippy::new_without_default)]
+impl<T: ?Sized> Probe<T> {
+    pub const fn new() -> Self {
+        Probe(PhantomData)
+    }
+}
+
+// Method resolution tries `&Probe<T>` before autoref'ing to `&&Probe<T>`,
+// so AbiKnown wins whenever its bound holds
+#[doc(hidden)]
+pub trait AbiKnown {
+    fn fingerprint(&self) -> u64;
+}
+impl<T: ?Sized + HotpatchAbi> AbiKnown for Probe<T> {
+    fn fingerprint(&self) -> u64 {
+        T::FINGERPRINT
+    }
+}
+
+#[doc(hidden)]
+pub trait AbiUnknown {
+    fn fingerprint(&self) -> u64;
+}
+impl<T: ?Sized> AbiUnknown for &Probe<T> {
+    fn fingerprint(&self) -> u64 {
+        0
+    }
+}
+
+macro_rules! abi_primitive {
+    ($($t:ty),*) => {
+        $(impl HotpatchAbi for $t {
+            const FINGERPRINT: u64 = Fingerprint::new(stringify!($t)).layout::<Self>().finish();
+        })*
+    }
+}
+abi_primitive!(
+    (), bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64,
+    String
+);
+
+impl HotpatchAbi for str {
+    const FINGERPRINT: u64 = Fingerprint::new("str").finish();
+}
+
+// wrappers around a single type parameter
+macro_rules! abi_wrapper {
+    ($($name:literal $t:ty),*) => {
+        $(impl<T: ?Sized + HotpatchAbi> HotpatchAbi for $t {
+            const FINGERPRINT: u64 = Fingerprint::new($name)
+                .layout::<Self>()
+                .u64(T::FINGERPRINT)
+                .finish();
+        })*
+    }
+}
+abi_wrapper!(
+    "&" &T,
+    "&mut" &mut T,
+    "*const" *const T,
+    "*mut" *mut T,
+    "Box" Box<T>,
+    "Rc" std::rc::Rc<T>,
+    "Arc" std::sync::Arc<T>
+);
+
+macro_rules! abi_sized_wrapper {
+    ($($name:literal $t:ty),*) => {
+        $(impl<T: HotpatchAbi> HotpatchAbi for $t {
+            const FINGERPRINT: u64 = Fingerprint::new($name)
+                .layout::<Self>()
+                .u64(T::FINGERPRINT)
+                .finish();
+        })*
+    }
+}
+abi_sized_wrapper!(
+    "Option" Option<T>,
+    "Vec" Vec<T>,
+    "BoxFuture" BoxFuture<T>
+);
+
+impl<T: HotpatchAbi> HotpatchAbi for [T] {
+    const FINGERPRINT: u64 = Fingerprint::new("[]").u64(T::FINGERPRINT).finish();
+}
+
+impl<T: HotpatchAbi, const N: usize> HotpatchAbi for [T; N] {
+    const FINGERPRINT: u64 = Fingerprint::new("[;]")
+        .layout::<Self>()
+        .u64(T::FINGERPRINT)
+        .finish();
+}
+
+impl<T: HotpatchAbi, E: HotpatchAbi> HotpatchAbi for Result<T, E> {
+    const FINGERPRINT: u64 = Fingerprint::new("Result")
+        .layout::<Self>()
+        .u64(T::FINGERPRINT)
+        .u64(E::FINGERPRINT)
+        .finish();
+}
+
+macro_rules! abi_tuple {
+    ($(($($t:ident $i:tt),+))*) => {
+        $(impl<$($t: HotpatchAbi),+> HotpatchAbi for ($($t,)+) {
+            const FINGERPRINT: u64 = Fingerprint::new("()")
+                .layout::<Self>()
+                $(.field(stringify!($i), std::mem::offset_of!(Self, $i), $t::FINGERPRINT))+
+                .finish();
+        })*
+    }
+}
+abi_tuple! {
+    (A 0)
+    (A 0, B 1)
+    (A 0, B 1, C 2)
+    (A 0, B 1, C 2, D 3)
+    (A 0, B 1, C 2, D 3, E 4)
+    (A 0, B 1, C 2, D 3, E 4, F 5)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the same type as built into the binary and into a library
+    mod host {
+        #[derive(crate::HotpatchAbi)]
+        pub struct Foo {
+            pub a: u32,
+            pub b: &'static str,
+        }
+
+        #[allow(dead_code)]
+        #[derive(crate::HotpatchAbi)]
+        pub enum Event {
+            Start,
+            Stop(u32),
+        }
+    }
+
+    mod same {
+        #[derive(crate::HotpatchAbi)]
+        pub struct Foo {
+            pub a: u32,
+            pub b: &'static str,
+        }
+    }
+
+    mod widened {
+        #[derive(crate::HotpatchAbi)]
+        pub struct Foo {
+            pub a: u64,
+            pub b: &'static str,
+        }
+    }
+
+    mod reordered {
+        #[derive(crate::HotpatchAbi)]
+        pub struct Foo {
+            pub b: &'static str,
+            pub a: u32,
+        }
+    }
+
+    mod renamed {
+        #[allow(dead_code)]
+        #[derive(crate::HotpatchAbi)]
+        pub enum Event {
+            Begin,
+            Stop(u32),
+        }
+    }
+
+    #[test]
+    fn layout_changes_change_the_fingerprint() {
+        assert_eq!(host::Foo::FINGERPRINT, same::Foo::FINGERPRINT);
+        assert_ne!(host::Foo::FINGERPRINT, widened::Foo::FINGERPRINT);
+        assert_ne!(host::Foo::FINGERPRINT, reordered::Foo::FINGERPRINT);
+        assert_ne!(host::Event::FINGERPRINT, renamed::Event::FINGERPRINT);
+        // and so does anything containing them
+        assert_ne!(
+            <Option<host::Foo>>::FINGERPRINT,
+            <Option<widened::Foo>>::FINGERPRINT
+        );
+        assert_ne!(<&u32>::FINGERPRINT, <&u64>::FINGERPRINT);
+        assert_ne!(<(u8, u16)>::FINGERPRINT, <(u16, u8)>::FINGERPRINT);
+    }
+
+    struct Opaque;
+
+    #[test]
+    #[allow(clippy::needless_borrow)] // called the way the macros call it
+    fn probe_falls_back_to_zero() {
+        assert_eq!((&Probe::<host::Foo>::new()).fingerprint(), host::Foo::FINGERPRINT);
+        assert_eq!((&Probe::<Opaque>::new()).fingerprint(), 0);
+        assert_ne!(
+            signature(&[u32::FINGERPRINT, <()>::FINGERPRINT]),
+            signature(&[<()>::FINGERPRINT, u32::FINGERPRINT])
+        );
+    }
+}
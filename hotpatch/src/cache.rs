@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::stamp::check;
+use crate::{HotpatchError, Lazy};
+
+/// When libraries are copied into the cache directory before being loaded.
+///
+/// `dlopen` hands back the library it already has open when given the same path
+/// again, so reloading a rebuilt library from its original path silently keeps
+/// the old code. Loading from a copy named after the file's contents avoids this.
+/// Copies are deleted once the library is closed.
+///
+/// Set with [`set_copy_on_load`](set_copy_on_load).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CopyOnLoad {
+    /// Always load straight from the given path.
+    Never,
+    /// Copy a library only if it's already loaded from the same path. This is the default.
+    #[default]
+    Reload,
+    /// Always copy.
+    Always,
+}
+
+struct Config {
+    mode: CopyOnLoad,
+    dir: Option<PathBuf>,
+}
+
+static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
+    RwLock::new(Config {
+        mode: CopyOnLoad::default(),
+        dir: None,
+    })
+});
+
+/// Choose when libraries are copied before loading. Applies to every load after this call.
+pub fn set_copy_on_load(mode: CopyOnLoad) {
+    CONFIG.write().unwrap().mode = mode;
+}
+
+/// Set the directory libraries are copied to. Defaults to a `hotpatch-<pid>` directory
+/// in [`std::env::temp_dir`](std::env::temp_dir), which is removed whenever the last copy
+/// in it is deleted. A directory set here is left in place.
+pub fn set_cache_dir<P: Into<PathBuf>>(dir: P) {
+    CONFIG.write().unwrap().dir = Some(dir.into());
+}
+
+// How many open libraries were loaded from each path, whether the original or a copy.
+// A copy is deleted when its count drops to zero.
+static OPEN: Lazy<Mutex<HashMap<PathBuf, usize>>> = Lazy::new(Default::default);
+
+/// A loaded library, along with the cached copy it was loaded from, if any.
+#[doc(hidden)]
+pub struct LoadedLibrary {
+    lib: Option<libloading::Library>, // only None while closing
+    path: PathBuf,
+    copy: bool,
+    pinned: AtomicBool,
+}
+
+impl LoadedLibrary {
+    /// Keep the library loaded until the process exits. Only the first call does anything,
+    /// so a library that panics many times is still only kept once.
+    pub(crate) fn pin(self: &Arc<Self>) {
+        if !self.pinned.swap(true, Ordering::Relaxed) {
+            event!(DEBUG, path = %self.path.display(), "pinned library");
+            std::mem::forget(self.clone());
+        }
+    }
+    /// Close the library now, rather than when it's dropped, to find out if that failed.
+    pub(crate) fn close(mut self) -> Result<(), HotpatchError> {
+        let result = self
+            .lib
+            .take()
+            .unwrap()
+            .close()
+            .map_err(|source| HotpatchError::LibraryClose { source });
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(()) => event!(INFO, path = %self.path.display(), "closed library"),
+            Err(e) => event!(WARN, path = %self.path.display(), error = %e, "failed to close library"),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+impl LoadedLibrary {
+    // Counted as open like a loaded library, but with nothing actually opened
+    pub(crate) fn unopened(path: PathBuf, copy: bool) -> Self {
+        *OPEN.lock().unwrap().entry(path.clone()).or_insert(0) += 1;
+        Self {
+            lib: None,
+            path,
+            copy,
+            pinned: AtomicBool::new(false),
+        }
+    }
+}
+
+impl std::ops::Deref for LoadedLibrary {
+    type Target = libloading::Library;
+    fn deref(&self) -> &Self::Target {
+        self.lib.as_ref().unwrap()
+    }
+}
+
+impl Drop for LoadedLibrary {
+    fn drop(&mut self) {
+        // the library has to be closed before its file can go
+        if self.lib.take().is_some() {
+            event!(INFO, path = %self.path.display(), "closed library");
+        }
+        let mut open = OPEN.lock().unwrap();
+        let count = open.get_mut(&self.path).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            open.remove(&self.path);
+            if self.copy {
+                remove_copy(&self.path);
+            }
+        }
+    }
+}
+
+pub(crate) fn load_library(lib_name: &str) -> Result<LoadedLibrary, HotpatchError> {
+    let mode = CONFIG.read().unwrap().mode;
+    load(lib_name, mode)
+}
+
+pub(crate) fn load(lib_name: &str, mode: CopyOnLoad) -> Result<LoadedLibrary, HotpatchError> {
+    let _span = span!(INFO, "load", lib = lib_name);
+    let original = Path::new(lib_name);
+    // names without a path are found by the system, and can't be copied
+    let path = match original.canonicalize() {
+        Ok(path) if path.is_file() => path,
+        _ => PathBuf::from(lib_name),
+    };
+    let mut open = OPEN.lock().unwrap();
+    let copy = match mode {
+        CopyOnLoad::Never => false,
+        CopyOnLoad::Reload => open.contains_key(&path) && path.is_file(),
+        CopyOnLoad::Always => path.is_file(),
+    };
+    let path = if copy { copy_to_cache(&path)? } else { path };
+    // the stamp can only be read once the library is open, so its initialisers have
+    // already run even if it's then rejected
+    let lib = libloading::Library::new(&path)
+        .map_err(|source| HotpatchError::LibraryLoad {
+            lib: lib_name.to_owned(),
+            source,
+        })
+        .and_then(|lib| check(&lib, lib_name).map(|()| lib));
+    let lib = match lib {
+        Ok(lib) => lib,
+        Err(e) => {
+            event!(WARN, error = %e, "failed to load library");
+            if copy && !open.contains_key(&path) {
+                remove_copy(&path);
+            }
+            return Err(e);
+        }
+    };
+    event!(INFO, path = %path.display(), copy, "loaded library");
+    *open.entry(path.clone()).or_insert(0) += 1;
+    Ok(LoadedLibrary {
+        lib: Some(lib),
+        path,
+        copy,
+        pinned: AtomicBool::new(false),
+    })
+}
+
+fn copy_to_cache(lib: &Path) -> Result<PathBuf, HotpatchError> {
+    let io_error = |path: &Path| {
+        let path = path.to_owned();
+        move |source| HotpatchError::Io { path, source }
+    };
+    let bytes = std::fs::read(lib).map_err(io_error(lib))?;
+    let dir = match &CONFIG.read().unwrap().dir {
+        Some(dir) => dir.clone(),
+        None => default_cache_dir(),
+    };
+    std::fs::create_dir_all(&dir).map_err(io_error(&dir))?;
+    // identical contents share a copy, which is fine as they're the same code
+    let name = lib.file_name().unwrap().to_string_lossy();
+    let copy = dir.join(format!("{:016x}-{}", fnv1a(&bytes), name));
+    if !copy.is_file() {
+        // written under another name first, so a copy is never seen half-written
+        let part = dir.join(format!("{:016x}-{}.part", fnv1a(&bytes), name));
+        std::fs::write(&part, &bytes).map_err(io_error(&part))?;
+        std::fs::rename(&part, &copy).map_err(io_error(&copy))?;
+    }
+    Ok(copy)
+}
+
+fn default_cache_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("hotpatch-{}", std::process::id()))
+}
+
+// Delete a copy that's no longer open, and the default cache directory along with
+// it if that's now empty. Called with OPEN locked, so no copy is being made meanwhile.
+fn remove_copy(copy: &Path) {
+    let _ = std::fs::remove_file(copy);
+    match copy.parent() {
+        // fails if other copies are still there, which is fine
+        Some(dir) if dir == default_cache_dir() => {
+            let _ = std::fs::remove_dir(dir);
+        }
+        _ => {}
+    }
+}
+
+// FNV-1a, the same hash the macros name exports with
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a file to load, outside the cache directory
+    fn library(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hotpatch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn copies_are_deleted_once_closed() {
+        let original = library("libclosed.so", "copies_are_deleted_once_closed");
+        let copy = {
+            let _open = OPEN.lock().unwrap();
+            copy_to_cache(&original).unwrap()
+        };
+        assert_eq!(copy.parent().unwrap(), default_cache_dir());
+        assert_eq!(std::fs::read(&copy).unwrap(), std::fs::read(&original).unwrap());
+        // identical contents share a copy
+        let first = LoadedLibrary::unopened(copy.clone(), true);
+        let second = LoadedLibrary::unopened(copy.clone(), true);
+        drop(first);
+        assert!(copy.is_file());
+        drop(second);
+        assert!(!copy.exists());
+        std::fs::remove_file(original).unwrap();
+    }
+
+    #[test]
+    fn failed_loads_leave_no_copy() {
+        let original = library("libbroken.so", "failed_loads_leave_no_copy");
+        let bytes = std::fs::read(&original).unwrap();
+        let copy = default_cache_dir().join(format!("{:016x}-libbroken.so", fnv1a(&bytes)));
+        let result = load(original.to_str().unwrap(), CopyOnLoad::Always);
+        assert!(matches!(result, Err(HotpatchError::LibraryLoad { .. })));
+        assert!(!copy.exists());
+        assert!(!OPEN.lock().unwrap().contains_key(&copy));
+        std::fs::remove_file(original).unwrap();
+    }
+}
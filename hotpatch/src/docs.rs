@@ -13,6 +13,7 @@ where
     ///
     /// ## Example
     /// ```
+    /// # use hotpatch::*;
     /// #[patchable]
     /// fn foo(_: i32, _: i32, _: i32) {}
     ///
@@ -34,7 +35,7 @@ where
     /// If using functions with large numbers of inputs and `hotpatch_fn` does not
     /// appear to be defined, compile `hotpatch` with the `large-signatures` feature
     /// to increase the number of supported arguements.
-    pub fn hotpatch_fn<F>(&self, ptr: F) -> Result<(), Box<dyn std::error::Error + '_>>
+    pub fn hotpatch_fn<F>(&self, ptr: F) -> Result<(), crate::HotpatchError>
     where
         F: Fn(VaGen) -> Ret,
     {
@@ -42,7 +43,7 @@ where
     }
     /// Like [`hotpatch_fn`](crate::Patchable::hotpatch_fn) but uses
     /// [`RwLock::try_write`](https://doc.rust-lang.org/std/sync/struct.RwLock.html#method.try_write).
-    pub fn try_hotpatch_fn<F>(&self, ptr: F) -> Result<(), Box<dyn std::error::Error + '_>>
+    pub fn try_hotpatch_fn<F>(&self, ptr: F) -> Result<(), crate::HotpatchError>
     where
         F: Fn(VaGen) -> Ret,
     {
@@ -62,7 +63,7 @@ where
     /// if a thread tries to call a `Patchable` during a (small but nonzero duration) `force` transition.
     ///
     /// **Use with caution**.
-    pub unsafe fn force_hotpatch_fn<F>(&self, ptr: F) -> Result<(), Box<dyn std::error::Error + '_>>
+    pub unsafe fn force_hotpatch_fn<F>(&self, ptr: F) -> Result<(), crate::HotpatchError>
     where
         F: Fn(VaGen) -> Ret,
     {
@@ -73,7 +74,8 @@ where
     /// finding the definition that matches module path and signature.
     ///
     /// ## Example
-    /// ```
+    /// ```no_run
+    /// # use hotpatch::*;
     /// #[patchable]
     /// fn foo() {}
     ///
@@ -94,12 +96,12 @@ where
     /// If using functions with large numbers of inputs and `hotpatch_lib` does not
     /// appear to be defined, compile `hotpatch` with the `large-signatures` feature
     /// to increase the number of supported arguements.
-    pub fn hotpatch_lib(&self, lib_name: &str) -> Result<(), Box<dyn std::error::Error + '_>> {
+    pub fn hotpatch_lib(&self, lib_name: &str) -> Result<(), crate::HotpatchError> {
         // The actual implementation is in toplevel
     }
     /// Like [`hotpatch_lib`](crate::Patchable::hotpatch_lib) but uses
     /// [`RwLock::try_write`](https://doc.rust-lang.org/std/sync/struct.RwLock.html#method.try_write).
-    pub fn try_hotpatch_lib(&self, lib_name: &str) -> Result<(), Box<dyn std::error::Error + '_>> {
+    pub fn try_hotpatch_lib(&self, lib_name: &str) -> Result<(), crate::HotpatchError> {
         // The actual implementation is in toplevel
     }
     /// Like [`hotpatch_lib`](crate::Patchable::hotpatch_lib) but uses
@@ -119,7 +121,7 @@ where
     pub unsafe fn force_hotpatch_lib(
         &self,
         lib_name: &str,
-    ) -> Result<(), Box<dyn std::error::Error + '_>> {
+    ) -> Result<(), crate::HotpatchError> {
         // The actual implementation is in toplevel
     }
 }
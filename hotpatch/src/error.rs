@@ -0,0 +1,163 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{PoisonError, TryLockError};
+
+/// Everything that can go wrong while hotpatching.
+///
+/// Unlike a locked [`RwLock`](https://doc.rust-lang.org/std/sync/struct.RwLock.html)'s
+/// errors this owns all of its data, so it can be sent between threads or stored.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum HotpatchError {
+    /// The shared object could not be opened.
+    LibraryLoad {
+        lib: String,
+        source: libloading::Error,
+    },
+    /// The shared object replaced by a hotpatch could not be closed.
+    LibraryClose { source: libloading::Error },
+    /// The shared object has no manifest, so was not built with [`#[patch]`](crate::patch).
+    NotAPatchLibrary { lib: String },
+    /// The shared object was built with an incompatible version of `hotpatch`.
+    ManifestVersion { lib: String, expected: u32, found: u32 },
+    /// The shared object was built differently from this binary, such as by another
+    /// compiler. It was opened to read its stamp, so its initialisers have run, but
+    /// nothing else was read from it. See [`BuildCheck`](crate::BuildCheck).
+    BuildMismatch {
+        lib: String,
+        /// Which part of the build differs, such as `rustc`
+        field: &'static str,
+        expected: String,
+        found: String,
+    },
+    /// The shared object has no export for this module path.
+    SymbolMissing {
+        symbol: String,
+        lib: String,
+        /// Module paths of everything the library does export
+        available: Vec<String>,
+    },
+    /// An export was found, but with a different signature.
+    SignatureMismatch {
+        symbol: String,
+        expected: String,
+        found: String,
+    },
+    /// An export was found with the same signature, but a type in it has a different
+    /// layout in the library. See [`HotpatchAbi`](crate::HotpatchAbi).
+    LayoutMismatch { symbol: String, sig: String },
+    /// [`rollback`](crate::Patchable::rollback) was called with no previous definition left.
+    NoHistory,
+    /// The wrapper given to [`remove_wrapper`](crate::Patchable::remove_wrapper), or any
+    /// wrapper for [`pop_wrapper`](crate::Patchable::pop_wrapper), isn't part of the
+    /// current definition.
+    NotWrapped,
+    /// A `try` method would have had to wait for the lock.
+    WouldBlock,
+    /// A thread panicked while holding a lock that can't recover from it.
+    /// A [`Patchable`](crate::Patchable)'s own lock always recovers.
+    Poisoned,
+    /// A file operation on a library failed, such as copying it before loading.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// A [`Watcher`](crate::watch::Watcher) could not watch a path.
+    #[cfg(feature = "watch")]
+    Watch { source: notify::Error },
+}
+
+impl fmt::Display for HotpatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use HotpatchError::*;
+        match self {
+            LibraryLoad { lib, source } => write!(f, "Could not load library {}: {}", lib, source),
+            LibraryClose { source } => write!(f, "Could not close library: {}", source),
+            NotAPatchLibrary { lib } => write!(
+                f,
+                "Library {} has no hotpatch manifest. Is it a patch library?",
+                lib
+            ),
+            ManifestVersion {
+                lib,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Library {} has manifest version {} but version {} was expected",
+                lib, found, expected
+            ),
+            BuildMismatch {
+                lib,
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Library {} was built with {} {} but this binary has {}",
+                lib, field, found, expected
+            ),
+            SymbolMissing {
+                symbol,
+                lib,
+                available,
+            } => write!(
+                f,
+                "Hotpatch for {} failed: symbol not found in library {}. The library exports: [{}]",
+                symbol,
+                lib,
+                available.join(", ")
+            ),
+            SignatureMismatch {
+                symbol,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Hotpatch for {} failed: symbol found but of wrong type. Expected {} but found {}",
+                symbol, expected, found
+            ),
+            LayoutMismatch { symbol, sig } => write!(
+                f,
+                "Hotpatch for {} failed: a type in {} has a different layout in the library",
+                symbol, sig
+            ),
+            NoHistory => write!(f, "Rollback failed: there is no previous definition"),
+            NotWrapped => write!(f, "Unwrap failed: the current definition has no such wrapper"),
+            WouldBlock => write!(f, "Hotpatch failed: the lock is currently held"),
+            Poisoned => write!(f, "Hotpatch failed: the lock is poisoned"),
+            Io { path, source } => write!(f, "Could not access {}: {}", path.display(), source),
+            #[cfg(feature = "watch")]
+            Watch { source } => write!(f, "Could not watch library: {}", source),
+        }
+    }
+}
+
+impl std::error::Error for HotpatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HotpatchError::LibraryLoad { source, .. } | HotpatchError::LibraryClose { source } => {
+                Some(source)
+            }
+            HotpatchError::Io { source, .. } => Some(source),
+            #[cfg(feature = "watch")]
+            HotpatchError::Watch { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for HotpatchError {
+    fn from(_: PoisonError<T>) -> Self {
+        HotpatchError::Poisoned
+    }
+}
+
+impl<T> From<TryLockError<T>> for HotpatchError {
+    fn from(e: TryLockError<T>) -> Self {
+        match e {
+            TryLockError::WouldBlock => HotpatchError::WouldBlock,
+            TryLockError::Poisoned(_) => HotpatchError::Poisoned,
+        }
+    }
+}
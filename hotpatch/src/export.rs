@@ -1,16 +1,211 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+
+use crate::{HotpatchError, OriginalSlot};
+
 /// Created by [`#[patch]`](crate::patch). Internal use only.
 ///
-/// Creates a `#[no_mangle] pub static` instance to be imported in another
-/// binary by [`Patchable`](crate::Patchable) methods.
+/// Creates a `pub static` instance to be imported in another binary by
+/// [`Patchable`](crate::Patchable) methods. It's found through the library's
+/// [`HotpatchManifest`](HotpatchManifest) rather than by name.
 pub struct HotpatchExport<T: 'static> {
     pub symbol: &'static str,
     pub sig: &'static str,
+    /// Layout fingerprint of the signature, see [`HotpatchAbi`](crate::HotpatchAbi)
+    pub abi: fn() -> u64,
+    /// Made by `#[patch(wrap)]`, so `ptr` takes the previous definition first
+    pub wrap: bool,
     pub ptr: T,
+    /// Where the body's [`original!()`](crate::original) finds the default definition
+    pub original: Option<&'static dyn OriginalSlot>,
 }
 
 #[doc(hidden)]
 impl<T: 'static> HotpatchExport<T> {
-    pub const fn __new(ptr: T, symbol: &'static str, sig: &'static str) -> Self {
-        Self { symbol, sig, ptr }
+    pub const fn __new(
+        ptr: T,
+        symbol: &'static str,
+        sig: &'static str,
+        abi: fn() -> u64,
+        wrap: bool,
+        original: Option<&'static dyn OriginalSlot>,
+    ) -> Self {
+        Self {
+            symbol,
+            sig,
+            abi,
+            wrap,
+            ptr,
+            original,
+        }
+    }
+}
+
+/// Created by [`#[patch]`](crate::patch). Internal use only.
+///
+/// A [`HotpatchExport`](HotpatchExport) with its function type erased, so that
+/// every export in a library can be listed in its [`HotpatchManifest`](HotpatchManifest).
+pub struct HotpatchExportEntry {
+    pub symbol: &'static str,
+    pub sig: &'static str,
+    pub abi: fn() -> u64,
+    pub wrap: bool,
+    pub original: Option<&'static dyn OriginalSlot>,
+    export: *const (),
+}
+
+// export only ever points to a HotpatchExport, which is immutable
+unsafe impl Sync for HotpatchExportEntry {}
+
+#[doc(hidden)]
+impl HotpatchExportEntry {
+    pub const fn __new<T>(export: &'static HotpatchExport<T>) -> Self {
+        Self {
+            symbol: export.symbol,
+            sig: export.sig,
+            abi: export.abi,
+            wrap: export.wrap,
+            original: export.original,
+            export: export as *const HotpatchExport<T> as *const (),
+        }
+    }
+    /// # Safety
+    /// `T` must be the function type this entry was created with. Check `sig` first.
+    pub(crate) unsafe fn export<T>(&self) -> &HotpatchExport<T> {
+        &*(self.export as *const HotpatchExport<T>)
+    }
+}
+
+/// Every [`#[patch]`](crate::patch) in the final binary, collected at link time.
+/// Internal use only.
+#[doc(hidden)]
+#[linkme::distributed_slice]
+pub static HOTPATCH_EXPORTS: [HotpatchExportEntry] = [..];
+
+/// Version of [`HotpatchManifest`](HotpatchManifest). Bumped whenever its layout or the
+/// layout of [`HotpatchExportEntry`](HotpatchExportEntry) changes.
+pub const MANIFEST_VERSION: u32 = 5;
+
+/// Table of every export in a library, pointed to by the `__HOTPATCH_MANIFEST` symbol.
+/// `version` comes first so that it can be read whatever the rest looks like.
+#[repr(C)]
+pub struct HotpatchManifest {
+    pub version: u32,
+    pub count: usize,
+    pub exports: *const HotpatchExportEntry,
+}
+
+// exports only ever points to HOTPATCH_EXPORTS, which is immutable
+unsafe impl Send for HotpatchManifest {}
+unsafe impl Sync for HotpatchManifest {}
+
+static MANIFEST: Lazy<HotpatchManifest> = Lazy::new(|| HotpatchManifest {
+    version: MANIFEST_VERSION,
+    count: HOTPATCH_EXPORTS.len(),
+    exports: HOTPATCH_EXPORTS.as_ptr(),
+});
+
+/// The one well-known symbol of a patch library. Internal use only.
+///
+/// Uses the C ABI, so that a library built by another compiler can still be asked
+/// for its manifest version.
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __HOTPATCH_MANIFEST() -> *const HotpatchManifest {
+    &*MANIFEST
+}
+
+// The exports of a loaded library, indexed by module path (without the crate name)
+pub(crate) struct ManifestIndex<'a> {
+    exports: HashMap<&'a str, &'a HotpatchExportEntry>,
+}
+
+impl<'a> ManifestIndex<'a> {
+    /// # Safety
+    /// The index borrows from `lib`, and the library must be built with `hotpatch`.
+    pub(crate) unsafe fn read(
+        lib: &'a libloading::Library,
+        lib_name: &str,
+    ) -> Result<Self, HotpatchError> {
+        let manifest: libloading::Symbol<extern "C" fn() -> *const HotpatchManifest> = lib
+            .get(b"__HOTPATCH_MANIFEST")
+            .map_err(|_| HotpatchError::NotAPatchLibrary {
+                lib: lib_name.to_owned(),
+            })?;
+        Self::from_manifest(&*manifest(), lib_name)
+    }
+    /// # Safety
+    /// `exports` must point to `count` entries, if the version matches.
+    unsafe fn from_manifest(
+        manifest: &'a HotpatchManifest,
+        lib_name: &str,
+    ) -> Result<Self, HotpatchError> {
+        if manifest.version != MANIFEST_VERSION {
+            return Err(HotpatchError::ManifestVersion {
+                lib: lib_name.to_owned(),
+                expected: MANIFEST_VERSION,
+                found: manifest.version,
+            });
+        }
+        let entries: &'a [HotpatchExportEntry] = if manifest.count == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(manifest.exports, manifest.count)
+        };
+        Ok(Self {
+            exports: entries
+                .iter()
+                .map(|e| (e.symbol.trim_start_matches(|c| c != ':'), e))
+                .collect(),
+        })
+    }
+    pub(crate) fn get(&self, mpath: &str) -> Option<&'a HotpatchExportEntry> {
+        self.exports.get(mpath).copied()
+    }
+    /// Module paths of every export, for error messages
+    pub(crate) fn names(&self) -> Vec<&'a str> {
+        let mut names: Vec<_> = self.exports.keys().copied().collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[patch]
+    fn listed(a: i32) -> i32 {
+        a
+    }
+
+    #[test]
+    fn exports_are_found_by_module_path() {
+        let index = unsafe { ManifestIndex::from_manifest(&MANIFEST, "this") }.unwrap();
+        let entry = index.get("::export::tests::listed").unwrap();
+        assert_eq!(entry.symbol, "hotpatch::export::tests::listed");
+        assert_eq!(entry.sig, "fn(i32) -> i32");
+        assert!(!entry.wrap);
+        assert!(index.get("hotpatch::export::tests::listed").is_none());
+        let names = index.names();
+        assert!(names.contains(&"::export::tests::listed"));
+        assert!(names.windows(2).all(|w| w[0] <= w[1]));
+        let export: &HotpatchExport<fn(i32) -> i32> = unsafe { entry.export() };
+        assert_eq!((export.ptr)(3), 3);
+    }
+
+    #[test]
+    fn other_manifest_versions_are_rejected() {
+        let manifest = HotpatchManifest {
+            version: MANIFEST_VERSION + 1,
+            count: 0,
+            exports: std::ptr::null(),
+        };
+        assert!(matches!(
+            unsafe { ManifestIndex::from_manifest(&manifest, "libnewer.so") },
+            Err(HotpatchError::ManifestVersion { found, .. }) if found == MANIFEST_VERSION + 1
+        ));
     }
 }
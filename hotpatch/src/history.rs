@@ -0,0 +1,187 @@
+use std::mem::transmute_copy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{PoisonError, TryLockError};
+
+use crate::panic::panicked;
+use crate::{Definition, FnVoid, HotpatchImportInternal, Lock, OnPanic};
+
+static LIMIT: AtomicUsize = AtomicUsize::new(8);
+
+/// Set how many previous definitions each [`Patchable`](crate::Patchable) keeps for
+/// [`rollback`](crate::Patchable::rollback). Defaults to 8.
+///
+/// A library stays loaded while any definition from it is kept, so with
+/// [`watch`](crate::watch) every reload holds on to another copy until it falls off
+/// the end. Set to 0 to keep nothing. Applies from the next hotpatch onwards.
+pub fn set_history_limit(limit: usize) {
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+pub(crate) fn history_limit() -> usize {
+    LIMIT.load(Ordering::Relaxed)
+}
+
+// A check on every value returned by a patched definition, set by
+// HealthCheck::set_health_check
+pub(crate) struct Health {
+    check: Box<FnVoid>, // a dyn Fn(&Ret) -> bool, erased like Definition::ptr
+    threshold: usize,
+    failures: AtomicUsize, // in a row, by the current definition
+}
+
+impl Health {
+    /// # Safety
+    /// `check` must be a `Box<dyn Fn(&Ret) -> bool + Send + Sync>`, transmuted.
+    pub(crate) unsafe fn new(check: Box<FnVoid>, threshold: usize) -> Self {
+        Self {
+            check,
+            threshold: threshold.max(1),
+            failures: AtomicUsize::new(0),
+        }
+    }
+    pub(crate) fn reset(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+    // Ret has to be the type check was created with, which the Patchable's type guarantees
+    unsafe fn healthy<Ret>(&self, ret: &Ret) -> bool {
+        let check: &(dyn Fn(&Ret) -> bool + Send + Sync) = transmute_copy(&self.check);
+        check(ret)
+    }
+    // Count a failure of def, rolling it back once there have been too many in a row.
+    // Like reverting a panic, this doesn't wait for the lock, and tries again on the next failure.
+    fn fail<RealType: ?Sized + Send + Sync + 'static>(
+        &self,
+        lock: &Lock<HotpatchImportInternal<RealType>>,
+        def: &Definition,
+    ) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 < self.threshold {
+            return;
+        }
+        let mut inner = match lock.try_write() {
+            Ok(inner) => inner,
+            Err(TryLockError::Poisoned(e)) => PoisonError::into_inner(e),
+            Err(TryLockError::WouldBlock) => {
+                event!(DEBUG, state = %def.state, "lock held, health check rollback skipped");
+                return;
+            }
+        };
+        if inner.is_current(def) {
+            let _ = inner.rollback();
+        }
+    }
+}
+
+// Calls run through here rather than straight to the definition, see Fn::call
+pub(crate) fn guarded(on_panic: OnPanic, health: Option<&Health>, def: &Definition) -> bool {
+    (on_panic != OnPanic::Propagate || health.is_some())
+        && def.state != crate::PatchState::Default
+}
+
+// What a guarded call does once its definition has returned or panicked
+pub(crate) fn checked<RealType: ?Sized + Send + Sync + 'static, Ret>(
+    lock: &Lock<HotpatchImportInternal<RealType>>,
+    path: &'static str,
+    on_panic: OnPanic,
+    health: Option<&Health>,
+    def: &Definition,
+    result: std::thread::Result<Ret>,
+) -> Ret {
+    if let Some(health) = health {
+        match &result {
+            Ok(ret) if unsafe { health.healthy(ret) } => health.reset(),
+            _ => health.fail(lock, def),
+        }
+    }
+    match result {
+        Ok(ret) => ret,
+        Err(payload) => panicked(lock, path, on_panic, def, payload),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[patchable]
+    fn version() -> i32 {
+        0
+    }
+
+    #[test]
+    fn rollback_walks_back_through_history() {
+        version.hotpatch_fn(|| 1).unwrap();
+        version.hotpatch_fn(|| 2).unwrap();
+        version.restore_default().unwrap();
+        assert_eq!(version(), 0);
+        version.rollback().unwrap();
+        assert_eq!(version(), 2);
+        version.rollback().unwrap();
+        assert_eq!(version(), 1);
+        version.rollback().unwrap();
+        assert_eq!(version(), 0);
+        assert!(matches!(version.rollback(), Err(HotpatchError::NoHistory)));
+        assert_eq!(version(), 0);
+    }
+
+    #[patchable]
+    fn parse(s: &str) -> Option<i32> {
+        s.parse().ok()
+    }
+
+    #[test]
+    fn health_check_rolls_back_after_threshold() {
+        parse.set_health_check(3, |r: &Option<i32>| r.is_some()).unwrap();
+        parse.hotpatch_fn(|s: &str| s.parse().ok().map(|n: i32| n * 2)).unwrap();
+        parse.hotpatch_fn(|_: &str| None).unwrap();
+        assert_eq!(parse("1"), None);
+        assert_eq!(parse("1"), None);
+        assert_eq!(parse("1"), None);
+        // three failures in a row, so the previous definition is back
+        assert_eq!(parse("1"), Some(2));
+        // the default definition isn't checked, whatever it returns
+        parse.rollback().unwrap();
+        for _ in 0..5 {
+            assert_eq!(parse("x"), None);
+        }
+        assert_eq!(parse("1"), Some(1));
+    }
+
+    #[patchable]
+    fn flaky(n: i32) -> i32 {
+        n
+    }
+
+    #[test]
+    fn health_check_counts_failures_in_a_row() {
+        flaky.set_health_check(2, |r: &i32| *r >= 0).unwrap();
+        flaky.hotpatch_fn(|n: i32| n + 100).unwrap();
+        flaky.hotpatch_fn(|n: i32| n).unwrap();
+        // a success between failures starts the count again
+        for _ in 0..3 {
+            assert_eq!(flaky(-1), -1);
+            assert_eq!(flaky(1), 1);
+        }
+        assert_eq!(flaky(-1), -1);
+        assert_eq!(flaky(-1), -1);
+        assert_eq!(flaky(1), 101);
+    }
+
+    #[patchable]
+    fn explode() -> i32 {
+        0
+    }
+
+    #[test]
+    fn health_check_counts_panics() {
+        explode.set_health_check(2, |_: &i32| true).unwrap();
+        explode.hotpatch_fn(|| 1).unwrap();
+        explode.hotpatch_fn(|| -> i32 { panic!("boom") }).unwrap();
+        for _ in 0..2 {
+            // a closure doesn't capture the static, so it's unwind safe with any lock
+            #[allow(clippy::redundant_closure)]
+            let panicked = std::panic::catch_unwind(|| explode()).is_err();
+            assert!(panicked);
+        }
+        assert_eq!(explode(), 1);
+    }
+}
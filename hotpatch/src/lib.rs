@@ -1,7 +1,5 @@
 #![feature(unboxed_closures)]
 #![feature(fn_traits)]
-#![feature(const_fn)]
-#![feature(const_fn_fn_ptr_basics)]
 
 //! Changing function definitions at runtime.
 //!
@@ -11,14 +9,14 @@
 //! ## Short Example
 //! The following shows how
 //! dead-simple this crate is to use:
-//! ```
+//! ```no_run
 //! // main.rs
 //! use hotpatch::*;
 //!
 //! #[patchable]
 //! fn foo() { }
 //!
-//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! fn main() -> Result<(), HotpatchError> {
 //!   foo(); // does nothing
 //!   foo.hotpatch_lib("libsomething.so")?;
 //!   foo(); // does something totally different!
@@ -32,6 +30,7 @@
 //!
 //! #[patch]
 //! fn foo() { }
+//! # fn main() {}
 //! ```
 //! For more examples see the [git repo](https://github.com/Shizcow/hotpatch).
 //!
@@ -39,9 +38,6 @@
 //!
 //! `hotpatch` also works with methods.
 //!
-//! **Note:** `hotpatch` currently only works with free functions (methods without a self parameter).
-//! Actual method support is in progress and expected in the next version.
-//!
 //! **Note:** [`#[patchable]`](patchable) and [`#[patch]`](patch) must be placed __outside__ of `impl` bodies!
 //!
 //! ### Example
@@ -60,6 +56,7 @@
 //! ```
 //! Patches are declared like this:
 //! ```
+//! # use hotpatch::*;
 //! struct Foo {}
 //!
 //! #[patch]
@@ -69,11 +66,18 @@
 //!         println!("this is external!");
 //!     }
 //! }
+//! # fn main() {}
 //! ```
 //!
 //! Finally, patching is done as so.
-//! ```
-//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! ```no_run
+//! # use hotpatch::*;
+//! # struct Foo {}
+//! # #[patchable]
+//! # impl Foo {
+//! #     pub fn bar() {}
+//! # }
+//! fn main() -> Result<(), HotpatchError> {
 //!     Foo::bar();
 //!     Foo::bar.hotpatch_fn(|| println!("this is patch!"))?;
 //!     Foo::bar();
@@ -83,12 +87,320 @@
 //! }
 //! ```
 //!
+//! ### Receivers
+//! Methods taking `self`, `&self` or `&mut self` are also supported. The receiver is
+//! lowered to an explicit first arguement, so `fn baz(&self, a: i32)` is stored as a
+//! `Patchable<dyn Fn(&Foo, i32)>`. Because `obj.baz()` must keep working, the
+//! [`Patchable`](Patchable) itself is found at `Foo::baz_patchable`:
+//! ```
+//! # use hotpatch::*;
+//! # struct Foo {}
+//! #[patchable]
+//! impl Foo {
+//!     pub fn baz(&self, a: i32) {
+//!         println!("I can be changed too! {}", a);
+//!     }
+//! }
+//!
+//! fn main() -> Result<(), HotpatchError> {
+//!     let foo = Foo {};
+//!     foo.baz(1);
+//!     Foo::baz_patchable.hotpatch_fn(|_: &Foo, a: i32| println!("this is patch! {}", a))?;
+//!     foo.baz(2);
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ### Trait Implementations
+//! `#[patchable] impl Trait for Type` works too, and calls through `dyn Trait` reach
+//! the current definition. As a trait impl can't hold extra items, the
+//! [`Patchable`](Patchable) for each method is placed in an inherent impl, named
+//! `{trait}_{method}_patchable` after the trait path in snake case and the method, and
+//! made `pub` so it's as visible as the type:
+//! ```no_run
+//! # use hotpatch::*;
+//! # struct Foo {}
+//! #[patchable]
+//! impl std::fmt::Display for Foo {
+//!     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+//!         write!(f, "Foo")
+//!     }
+//! }
+//!
+//! fn main() -> Result<(), HotpatchError> {
+//!     Foo::std_fmt_display_fmt_patchable.hotpatch_lib("target/debug/libmethods_obj.so")?;
+//!     Ok(())
+//! }
+//! ```
+//! The matching patch is a `#[patch] impl std::fmt::Display for Foo`. Exports are keyed by
+//! the trait path as written along with the type, so the patch has to name the trait the
+//! same way the [`#[patchable]`](patchable) impl does: `fmt::Display` won't match
+//! `std::fmt::Display`.
+//!
+//! ## Async Functions
+//! `async fn`s can be [`#[patchable]`](patchable) and [`#[patch]`](patch) as well. Their
+//! future is boxed, so `async fn foo(a: i32) -> String` is stored as a
+//! `Patchable<dyn Fn(i32) -> BoxFuture<String>>`, and is still called as `foo(1).await`.
+//! Replacement closures return a [`BoxFuture`](BoxFuture):
+//! ```
+//! # use hotpatch::*;
+//! #[patchable]
+//! async fn foo(a: i32) -> String {
+//!     a.to_string()
+//! }
+//!
+//! async fn bar() -> Result<(), HotpatchError> {
+//!     foo.hotpatch_fn(|a: i32| -> BoxFuture<String> {
+//!         Box::pin(async move { format!("patched {}", a) })
+//!     })?;
+//!     foo(1).await; // "patched 1"
+//!     Ok(())
+//! }
+//! # fn main() {}
+//! ```
+//! As the future is `'static`, arguements must be owned. Async methods are not yet supported.
+//!
+//! ## Generic Functions
+//! A generic function can't be stored in a single [`Patchable`](Patchable), so the
+//! types it will be used with are listed up front. One [`Patchable`](Patchable) is
+//! generated per instantiation, in a module named after the function:
+//! ```no_run
+//! # use hotpatch::*;
+//! #[patchable(instantiate(T = u32, T = String))]
+//! fn parse<T: std::str::FromStr + Default>(s: &str) -> T {
+//!     s.parse().unwrap_or_default()
+//! }
+//!
+//! fn main() -> Result<(), HotpatchError> {
+//!     parse::<u32>("12"); // still called like a generic function
+//!     parse::u32.hotpatch_fn(|s: &str| s.len() as u32)?;
+//!     parse::string.hotpatch_lib("libsomething.so")?;
+//!     Ok(())
+//! }
+//! ```
+//! Patches use the same list, `#[patch(instantiate(T = u32, T = String))]`, and export
+//! every instantiation. Calling the function with a type that isn't listed is a compile error.
+//!
+//! ## Patching Together
+//! Functions that depend on each other can be patched from one library in a single step
+//! with a [`PatchSet`](PatchSet). Every export is checked before anything is swapped, and
+//! the whole set can be rolled back at once:
+//! ```no_run
+//! # use hotpatch::*;
+//! # #[patchable]
+//! # fn foo() {}
+//! # #[patchable]
+//! # fn bar() {}
+//! fn main() -> Result<(), HotpatchError> {
+//!     let mut set = PatchSet::new("libsomething.so")?;
+//!     set.add(&foo)?.add(&bar)?; // fails here if either export is missing or mismatched
+//!     let patches = set.commit()?;
+//!     patches.rollback()?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ## Registry
+//! Every [`#[patchable]`](patchable) item in the binary is listed in a process-wide
+//! [`Registry`](Registry), along with its signature and what it's currently running:
+//! ```
+//! for item in hotpatch::registry().iter() {
+//!     println!("{} {}: {}", item.path(), item.sig(), item.state()); // "my_bin::foo fn() -> (): default"
+//! }
+//! let foo = hotpatch::registry().get("my_bin::foo");
+//! ```
+//! This is what [`apply_library`](apply_library) uses to hotpatch every item a library has
+//! an export for, loading it only once. It returns an [`ApplyReport`](ApplyReport) of what
+//! was applied, what had no matching item, and what had a mismatched signature.
+//!
+//! With the `stats` feature, [`RegistryEntry::stats`](RegistryEntry::stats) also counts the
+//! calls, panics and latency of each definition, to compare a patch with what it replaced.
+//!
+//! ## Reloading
+//! `dlopen` returns the library it already has open when asked for the same path again, so
+//! hotpatching from a library that was rebuilt in place would silently keep the old code.
+//! When a library is still open from the same path, it's instead copied into a cache
+//! directory under a name derived from its contents, and loaded from there. The copy is
+//! deleted once the library is closed. See [`CopyOnLoad`](CopyOnLoad) to change when this
+//! happens, and [`set_cache_dir`](set_cache_dir) to choose where.
+//!
+//! ## Layout Checks
+//! Signatures are compared by name, which can't tell that a struct gained a field since the
+//! library was built. Deriving [`HotpatchAbi`](HotpatchAbi) fingerprints a type's layout, and
+//! every arguement and return type with a fingerprint is checked as well:
+//! ```
+//! # use hotpatch::HotpatchAbi;
+//! #[derive(HotpatchAbi)]
+//! pub struct Foo {
+//!     pub description: &'static str,
+//! }
+//! ```
+//! A library built with a different `Foo` fails with
+//! [`HotpatchError::LayoutMismatch`](HotpatchError::LayoutMismatch) instead of being loaded.
+//! Primitives and common `std` types already implement it.
+//!
+//! ## Rolling Back
+//! Each [`Patchable`](Patchable) remembers the definitions it had before, so a bad patch
+//! can be undone with [`rollback`](Patchable::rollback) without losing the good one before
+//! it. A health check can do this automatically, after a number of calls in a row return
+//! something it rejects or panic:
+//! ```no_run
+//! # use hotpatch::*;
+//! # struct MyError;
+//! # #[patchable]
+//! # fn foo() -> Result<(), MyError> { Ok(()) }
+//! fn main() -> Result<(), HotpatchError> {
+//!     foo.set_health_check(3, |r: &Result<(), MyError>| r.is_ok())?;
+//!     foo.hotpatch_lib("libv1.so")?;
+//!     foo.hotpatch_lib("libv2.so")?; // goes back to libv1.so if it keeps failing
+//!     Ok(())
+//! }
+//! ```
+//! Libraries are kept loaded while they're in the history. See
+//! [`set_history_limit`](set_history_limit) to keep fewer.
+//!
+//! ## Temporary Patches
+//! [`scoped_patch`](HotpatchScoped::scoped_patch) patches a function until the returned
+//! guard is dropped, then puts back exactly what was there before. Guards nest, so a test
+//! can override a function that's already patched:
+//! ```
+//! # use hotpatch::*;
+//! #[patchable]
+//! fn now() -> u64 { 0 }
+//!
+//! fn main() -> Result<(), HotpatchError> {
+//!     let _fixed = now.scoped_patch(|| 1234)?;
+//!     assert_eq!(now(), 1234);
+//!     Ok(())
+//! } // now is restored here
+//! ```
+//!
+//! ## Wrapping
+//! [`wrap`](HotpatchWrap::wrap) patches a function with one that's handed the definition
+//! it replaces, to add behaviour around it rather than rewrite it:
+//! ```
+//! # use hotpatch::*;
+//! # #[patchable]
+//! # fn foo(a: i32) -> i32 { a }
+//! fn main() -> Result<(), HotpatchError> {
+//!     foo.wrap(|prev, a| {
+//!         println!("foo({})", a);
+//!         prev(a)
+//!     })?;
+//!     foo.pop_wrapper()?;
+//!     Ok(())
+//! }
+//! ```
+//! [`before`](HotpatchBefore::before) and [`after`](HotpatchAfter::after) are shorthands
+//! for wrappers that only look at the arguements or the return value.
+//!
+//! A library does the same with `#[patch(wrap)]`, taking the previous definition as its
+//! first arguement. Loading the same library again replaces its wrapper rather than
+//! adding another:
+//! ```
+//! # use hotpatch::*;
+//! # fn main() {}
+//! #[patch(wrap)]
+//! pub fn foo(prev: &dyn Fn(i32) -> i32, a: i32) -> i32 {
+//!     prev(a).max(0)
+//! }
+//! ```
+//! Any `#[patch]` can also call the host's default definition with
+//! [`original!()`](original), however it's been patched since:
+//! ```
+//! # use hotpatch::*;
+//! # fn main() {}
+//! #[patch]
+//! pub fn foo(a: i32) -> i32 {
+//!     if a < 0 {
+//!         return 0;
+//!     }
+//!     original!()(a)
+//! }
+//! ```
+//!
+//! ## Thread-Local Patches
+//! [`patch_thread_local`](HotpatchLocal::patch_thread_local) overrides a function on the
+//! calling thread only, so tests running in parallel can mock the same function without
+//! seeing each other's mocks:
+//! ```
+//! # use hotpatch::*;
+//! # #[patchable]
+//! # fn now() -> u64 { 0 }
+//! # fn main() { fixed_time() }
+//! fn fixed_time() {
+//!     let _fixed = now.patch_thread_local(|| 1234);
+//!     assert_eq!(now(), 1234);
+//! }
+//! ```
+//! Spawned threads run the global definition unless started with
+//! [`spawn_with_overrides`](spawn_with_overrides), or handed a
+//! [`LocalOverrides`](LocalOverrides) captured beforehand.
+//!
+//! The [`mock`](mock) module builds call expectations on top of this.
+//!
+//! ## Panics
+//! A panic in a patched definition unwinds through the [`Patchable`](Patchable) like any
+//! other. With `#[patchable(catch_panic)]` it's caught at the call boundary first and
+//! reported to the hook set by [`set_panic_hook`](set_panic_hook), and with
+//! `#[patchable(catch_panic(revert))]` the default definition is also put back, so later
+//! calls run the original. The panic then carries on to the caller with its original
+//! payload. That payload may have been made by the library, so a library that panics through
+//! a `catch_panic` function is kept loaded until the process exits.
+//! ```no_run
+//! # use hotpatch::*;
+//! #[patchable(catch_panic(revert))]
+//! fn foo() {}
+//!
+//! fn main() -> Result<(), HotpatchError> {
+//!     set_panic_hook(|p| eprintln!("{} panicked: {}", p.path, p.message));
+//!     foo.hotpatch_lib("libbuggy.so")?;
+//!     let _ = std::panic::catch_unwind(|| foo()); // reported, then caught here
+//!     foo(); // the original again
+//!     Ok(())
+//! }
+//! ```
+//! A panic can poison a [`Patchable`](Patchable)'s lock, but the definition is only ever
+//! changed by a single swap, so it can't be left half-updated. Calls and hotpatches go on
+//! using a poisoned lock as normal.
+//!
+//! ## Build Compatibility
+//! Rust's ABI isn't stable, so a patch library has to be built the same way as the binary
+//! loading it. Both carry a stamp of the `rustc` version, target, `hotpatch` version and
+//! features, panic strategy, and whether debug assertions were on. A library that differs
+//! fails to load with [`HotpatchError::BuildMismatch`](HotpatchError::BuildMismatch).
+//! Features are the exception: none of them change what a library shares with the binary,
+//! so they're only checked if asked for. If you know a difference is harmless, turn that
+//! part of the check off with [`set_build_check`](set_build_check).
+//!
+//! The stamp is read out of the library, so it's only checked once the library has been
+//! opened. By then anything the library runs when it's loaded, such as static
+//! constructors, has already run, even if it's then rejected. The check
+//! guards against calling into a library with the wrong ABI, not against loading one.
+//!
 //! ## Features
 //! For reference, this crate recognizes the following features:
-//! - `allow-main`: Allow setting `main` as [`#[patchable]`](patchable). Only useful if using `#[start]` or `#[main]`.
-//! - `redirect-main`: Same as `allow-main` but also generates a stub `#[main]` to call the [`Patchable`](Patchable).
-//!   If you just want to hotpatch `main`, this is probably the right feature. Requires nightly and `#[feature(main)]`.
+//! - `allow-main`: Allow setting `main` as [`#[patchable]`](patchable). Only useful if using `#![no_main]`
+//!   with your own entry point.
+//! - `redirect-main`: Same as `allow-main` but also generates an entry point to call the [`Patchable`](Patchable).
+//!   If you just want to hotpatch `main`, this is probably the right feature. Requires `#![no_main]`,
+//!   or `#![cfg_attr(not(test), no_main)]` so that `cargo test` still has its own entry point.
 //! - `large-signatures`: Tweaks the variadic generics engine. See [`hotpatch_fn`](Patchable::hotpatch_fn).
+//! - `lockfree`: Calls load the current definition from an atomic pointer instead of taking a read
+//!   lock. See [Lock-free Calls](#lock-free-calls).
+//! - `watch`: Adds [`watch::Watcher`](watch::Watcher), which reloads patch libraries whenever
+//!   they're rebuilt.
+//! - `stats`: Counts calls, panics and their latency for every definition, see
+//!   [`RegistryEntry::stats`](RegistryEntry::stats).
+//! - `tracing`: Emits [`tracing`](https://docs.rs/tracing) events with the target `hotpatch`
+//!   when libraries are loaded and closed, exports are resolved and checked, definitions
+//!   are swapped or force swapped, and when a hotpatch has to wait for calls or other
+//!   hotpatches to let go of a lock. Calls themselves aren't traced.
+//!
+//! ## Errors
+//! Every fallible method returns a [`HotpatchError`](HotpatchError). It owns its data and is
+//! `Send + Sync + 'static`, so it can be matched on, stored, or passed up as a
+//! `Box<dyn std::error::Error>` like in the examples above.
 //!
 //! ## Warnings
 //! Under normal operation, this crate provides type safety, thread safety,
@@ -106,33 +418,113 @@
 //! The `try` methods within [`Patchable`](Patchable) provide additional checks
 //! for this, but may cause other problems in multithreaded environments.
 //!
+//! ## Lock-free Calls
+//! With the `lockfree` feature, calling a [`Patchable`](Patchable) never blocks. Each call
+//! atomically loads the current definition and runs it, so hotpatching the current function
+//! (or one further up the call stack) no longer deadlocks. Patches are still serialized
+//! with each other.
+//!
+//! The tradeoff is that an out-of-date definition can keep running: calls that started before
+//! a hotpatch finish with the old definition, and only later calls see the new one. The old
+//! definition, and the library it was loaded from, is kept alive until the last such call
+//! returns, after which the library is closed.
+//!
 //! ## Bypassing Thread Safety
 //! The previous section mentions being unable to hotpatch currently running functions.
 //! This is a deliberate safety feature. However, it can be bypassed by using the
 //! `force` methods within [`Patchable`](Patchable). This allows multiple
 //! functions definitions to run at once. This is unsafe, but allows for some really
 //! interesting things such as hotpatching `main`.
+//!
+//! Each call keeps the definition it's running alive, so a library replaced by a `force`
+//! method is only closed once every call still inside it has returned.
 
 use std::marker::PhantomData;
 
-use simple_error::bail;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+#[cfg(not(feature = "lockfree"))]
 use std::sync::RwLock;
+use std::sync::{Arc, PoisonError, RwLockReadGuard, RwLockWriteGuard, TryLockError, Weak};
 
 pub use hotpatch_macros::*;
 #[doc(hidden)]
+pub use linkme;
+#[doc(hidden)]
 pub use once_cell::sync::Lazy;
 use variadic_generics::*;
 
+// so that the macros' `hotpatch::` paths resolve in this crate's own tests
+#[cfg(test)]
+extern crate self as hotpatch;
+
+#[macro_use]
+mod trace;
+
+mod error;
+pub use error::HotpatchError;
+
+#[doc(hidden)]
+pub mod abi;
+pub use abi::HotpatchAbi;
+
 mod export;
 pub use export::*;
+use export::ManifestIndex;
+
+mod cache;
+pub use cache::{set_cache_dir, set_copy_on_load, CopyOnLoad};
+#[doc(hidden)]
+pub use cache::LoadedLibrary;
+use cache::load_library;
+
+mod panic;
+pub use panic::{set_panic_hook, OnPanic, PatchPanic};
+
+mod scoped;
+pub use scoped::ScopedPatch;
+
+mod local;
+pub use local::{spawn_with_overrides, LocalOverrides, LocalPatch};
+
+mod history;
+use history::{checked, guarded, history_limit, Health};
+pub use history::set_history_limit;
+
+mod stamp;
+pub use stamp::{set_build_check, BuildCheck};
+
+mod patchset;
+pub use patchset::*;
+
+mod registry;
+pub use registry::*;
+
+mod target;
+use target::Target;
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+pub use stats::{CallStats, Latency};
+#[cfg(feature = "stats")]
+use stats::Stats;
+
+#[cfg(feature = "watch")]
+pub mod watch;
 
 mod docs;
-pub use docs::*;
 
 use std::mem::{transmute, transmute_copy};
 
 type FnVoid = dyn Fn() + Send + Sync + 'static;
 
+/// The return type of an `async fn` once it's [`#[patchable]`](patchable).
+///
+/// The future is boxed so that every definition, whether it is the original,
+/// a closure or from a library, has the same signature.
+pub type BoxFuture<T> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'static>>;
+
 macro_rules! va_largesig {
     ($va_len:tt, $va_idents:tt, $va_indices:tt, $($tt:tt)+) => {
 	#[cfg(not(feature = "large-signatures"))]
@@ -142,58 +534,366 @@ macro_rules! va_largesig {
     }
 }
 
+pub mod mock;
+
+mod wrap;
+use wrap::{Wrap, Wrapper};
+pub use wrap::{HotpatchAfter, HotpatchBefore, WrapId};
+
+mod original;
+pub use original::{Original, OriginalSlot};
+
 /// Created by [`#[patchable]`](patchable). A functor capable of overwriting its
 /// own function.
 pub struct Patchable<RealType: ?Sized + Send + Sync + 'static> {
-    lazy: Lazy<Option<RwLock<HotpatchImportInternal<RealType>>>>,
+    lazy: Lazy<Option<Lock<HotpatchImportInternal<RealType>>>>,
+}
+
+#[cfg(not(feature = "lockfree"))]
+#[doc(hidden)]
+pub type Lock<T> = RwLock<T>;
+#[cfg(feature = "lockfree")]
+mod lockfree;
+#[cfg(feature = "lockfree")]
+#[doc(hidden)]
+pub use lockfree::Lock;
+
+// a function definition and the library it was loaded from, if any
+#[doc(hidden)]
+pub struct Definition {
+    ptr: Box<FnVoid>, // void pointer
+    lib: Option<Arc<LoadedLibrary>>, // shared by every definition from one library
+    state: PatchState,
+    wrap: Option<Wrap>, // if this is a wrapper, what it wraps
+    #[cfg(feature = "stats")]
+    stats: Arc<Stats>, // every default definition of a Patchable shares one
+}
+
+impl Definition {
+    fn upcast<RealType: ?Sized>(&self) -> &RealType {
+        unsafe { transmute_copy(&self.ptr) }
+    }
+}
+
+// What HotpatchImportInternal stores and hands back on a swap. Every call holds
+// a reference to the definition it's running, so a definition and its library
+// outlive any call still inside them, even across a `force` swap.
+type SharedDefinition = Arc<Definition>;
+
+// Get rid of a definition that was swapped out, closing its library if nothing else uses
+// it. If a call is still running it, the last reference closes the library when dropped.
+fn retire(old: SharedDefinition) -> Result<(), HotpatchError> {
+    match Arc::try_unwrap(old).ok().and_then(|old| old.lib).map(Arc::try_unwrap) {
+        Some(Ok(lib)) => lib.close(),
+        _ => Ok(()),
+    }
 }
 
 #[doc(hidden)]
 pub struct HotpatchImportInternal<RealType: ?Sized + Send + Sync + 'static> {
-    current_ptr: Box<FnVoid>,       // void pointer
+    #[cfg(not(feature = "lockfree"))]
+    current: Arc<Definition>,
+    #[cfg(feature = "lockfree")]
+    current: Arc<arc_swap::ArcSwap<Definition>>, // shared with Lock
     default_ptr: Box<FnVoid>,       // void pointer
     phantom: PhantomData<RealType>, // store the real type for correct casts
     sig: &'static str,
-    lib: Option<libloading::Library>,
-    mpath: &'static str,
+    abi: u64, // layout fingerprint of sig
+    on_panic: OnPanic,
+    history: Vec<SharedDefinition>, // previous definitions, most recent last
+    // scoped patches that ended while patched over, and what to restore in their place
+    ended: Vec<(Weak<Definition>, SharedDefinition)>,
+    #[cfg(not(feature = "lockfree"))]
+    health: Option<Arc<Health>>,
+    #[cfg(feature = "lockfree")]
+    health: Arc<arc_swap::ArcSwapOption<Health>>, // shared with Lock
+    path: &'static str,  // full module path, for the registry
+    mpath: &'static str, // module path without the crate name, for exports
+    #[cfg(feature = "stats")]
+    default_stats: Arc<Stats>,
 }
 
 impl<RealType: ?Sized + Send + Sync + 'static> HotpatchImportInternal<RealType> {
-    fn new<T>(ptr: T, mpath: &'static str, sig: &'static str) -> Self {
+    fn new<T>(ptr: T, mpath: &'static str, sig: &'static str, abi: u64, on_panic: OnPanic) -> Self {
         // we know that ptr is a Box<'static raw fn ptr>, so it DOES impl Copy (kinda)
         // and because new is hidden, this assumption is safe
         let r = &ptr;
+        #[cfg(feature = "stats")]
+        let default_stats = Arc::new(Stats::default());
         unsafe {
+            let current = Definition {
+                ptr: transmute_copy(r),
+                lib: None,
+                state: PatchState::Default,
+                wrap: None,
+                #[cfg(feature = "stats")]
+                stats: default_stats.clone(),
+            };
             Self {
-                current_ptr: transmute_copy(r),
+                #[cfg(not(feature = "lockfree"))]
+                current: Arc::new(current),
+                #[cfg(feature = "lockfree")]
+                current: Arc::new(arc_swap::ArcSwap::from_pointee(current)),
                 default_ptr: transmute_copy(r),
                 phantom: PhantomData,
-                lib: None,
                 sig,
+                abi,
+                on_panic,
+                history: vec![],
+                ended: vec![],
+                #[cfg(not(feature = "lockfree"))]
+                health: None,
+                #[cfg(feature = "lockfree")]
+                health: Default::default(),
+                path: mpath,
                 mpath: mpath.trim_start_matches(|c| c != ':'),
+                #[cfg(feature = "stats")]
+                default_stats,
+            }
+        }
+    }
+    // swap in a new definition, handing back the old one
+    #[cfg(not(feature = "lockfree"))]
+    fn swap(&mut self, def: SharedDefinition) -> SharedDefinition {
+        if let Some(health) = &self.health {
+            health.reset();
+        }
+        event!(INFO, patchable = self.path, old = %self.current.state, new = %def.state, "swapped definition");
+        std::mem::replace(&mut self.current, def)
+    }
+    #[cfg(feature = "lockfree")]
+    fn swap(&mut self, def: SharedDefinition) -> SharedDefinition {
+        if let Some(health) = &*self.health.load() {
+            health.reset();
+        }
+        event!(INFO, patchable = self.path, old = %self.current.load().state, new = %def.state, "swapped definition");
+        self.current.swap(def)
+    }
+    #[cfg(not(feature = "lockfree"))]
+    fn current(&self) -> SharedDefinition {
+        self.current.clone()
+    }
+    #[cfg(feature = "lockfree")]
+    fn current(&self) -> SharedDefinition {
+        self.current.load_full()
+    }
+    // push a new definition. A wrapper goes over whatever is current by the time it's
+    // swapped in, which may have changed since it was made, as in a PatchSet. One loaded
+    // from an export that's already wrapping this takes the place of its earlier layer.
+    fn push(&mut self, def: SharedDefinition) -> SharedDefinition {
+        let def = match &def.wrap {
+            Some(wrap) => {
+                let reloaded = wrap.source.as_deref().and_then(|source| {
+                    self.rebuild(|w| w.source.as_deref() == Some(source), |prev| (wrap.rewrap)(prev).into())
+                });
+                match reloaded {
+                    Some(top) => top,
+                    None if !self.is_current(&wrap.prev) => (wrap.rewrap)(self.current()).into(),
+                    None => def,
+                }
             }
+            None => def,
+        };
+        self.install(def)
+    }
+    // swap in a new definition, keeping the old one in the history, and hand it back.
+    // Definitions that fall off the end are dropped rather than retired, so their
+    // libraries are closed once nothing uses them, but errors doing so are lost.
+    fn install(&mut self, def: SharedDefinition) -> SharedDefinition {
+        let old = self.swap(def);
+        self.history.push(old.clone());
+        let excess = self.history.len().saturating_sub(history_limit());
+        self.history.drain(..excess);
+        old
+    }
+    // swap back to a definition handed back by push, dropping it from the history,
+    // and hand back the one it replaces
+    fn restore(&mut self, def: SharedDefinition) -> SharedDefinition {
+        if let Some(i) = self.history.iter().rposition(|d| Arc::ptr_eq(d, &def)) {
+            self.history.remove(i);
         }
+        self.swap(def)
     }
-    fn clean(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        if self.lib.is_some() {
-            self.lib.take().unwrap().close()?;
+    // end a scoped patch, handing back the definition to retire if it was still current
+    fn unscope(
+        &mut self,
+        installed: SharedDefinition,
+        previous: SharedDefinition,
+    ) -> Option<SharedDefinition> {
+        // only kept while a scoped patch above still holds the definition as its previous
+        self.ended.retain(|(ended, _)| ended.strong_count() > 0);
+        let mut previous = previous;
+        while let Some(i) = self.ended.iter().position(|(e, _)| e.as_ptr() == Arc::as_ptr(&previous)) {
+            previous = self.ended.remove(i).1;
+        }
+        if self.is_current(&installed) {
+            return Some(self.restore(previous));
+        }
+        // patched over, so take it out from under whatever replaced it
+        if let Some(i) = self.history.iter().rposition(|d| Arc::ptr_eq(d, &installed)) {
+            self.history.remove(i);
+        }
+        self.ended.push((Arc::downgrade(&installed), previous));
+        None
+    }
+    // find the outermost wrapper in the current definition that `is_layer` picks, and
+    // rebuild the ones above it over what `replace` makes from the one below it
+    fn rebuild(
+        &self,
+        is_layer: impl Fn(&Wrap) -> bool,
+        replace: impl FnOnce(SharedDefinition) -> SharedDefinition,
+    ) -> Option<SharedDefinition> {
+        let mut above = vec![];
+        let mut def = self.current();
+        loop {
+            let wrap = def.wrap.as_ref()?;
+            if is_layer(wrap) {
+                break;
+            }
+            let prev = wrap.prev.clone();
+            above.push(def);
+            def = prev;
         }
+        let mut top = replace(def.wrap.as_ref().unwrap().prev.clone());
+        for def in above.iter().rev() {
+            top = (def.wrap.as_ref().unwrap().rewrap)(top).into();
+        }
+        Some(top)
+    }
+    // take a wrapper out of the current definition, rebuilding the ones above it over
+    // the one below. The outermost if id is None.
+    fn unwrap(&mut self, id: Option<WrapId>) -> Result<(), HotpatchError> {
+        let top = self
+            .rebuild(|wrap| id.is_none() || id == Some(wrap.id), |prev| prev)
+            .ok_or(HotpatchError::NotWrapped)?;
+        self.install(top);
         Ok(())
     }
-    fn restore_default(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    fn rollback(&mut self) -> Result<(), HotpatchError> {
+        let previous = self.history.pop().ok_or(HotpatchError::NoHistory)?;
+        retire(self.swap(previous))
+    }
+    // swap in a new definition that isn't from a library
+    fn replace(&mut self, ptr: Box<FnVoid>, state: PatchState) -> Result<(), HotpatchError> {
+        #[cfg(feature = "stats")]
+        let stats = match state {
+            PatchState::Default => self.default_stats.clone(),
+            _ => Default::default(),
+        };
+        self.push(
+            Definition {
+                ptr,
+                lib: None,
+                state,
+                wrap: None,
+                #[cfg(feature = "stats")]
+                stats,
+            }
+            .into(),
+        );
+        Ok(())
+    }
+    fn restore_default(&mut self) -> Result<(), HotpatchError> {
         // see Self::new for why this is safe
-        self.current_ptr = unsafe { transmute_copy(&self.default_ptr) };
-        self.clean()
+        let ptr = unsafe { transmute_copy(&self.default_ptr) };
+        self.replace(ptr, PatchState::Default)
+    }
+    #[cfg(not(feature = "lockfree"))]
+    fn state(&self) -> PatchState {
+        self.current.state.clone()
+    }
+    #[cfg(feature = "lockfree")]
+    fn state(&self) -> PatchState {
+        self.current.load().state.clone()
+    }
+    #[cfg(not(feature = "lockfree"))]
+    fn set_health(&mut self, health: Option<Health>) {
+        self.health = health.map(Arc::new);
+    }
+    #[cfg(feature = "lockfree")]
+    fn set_health(&mut self, health: Option<Health>) {
+        self.health.store(health.map(Arc::new));
     }
-    fn upcast_self(&self) -> &RealType {
-        unsafe { transmute_copy(&self.current_ptr) }
+    #[cfg(not(feature = "lockfree"))]
+    fn is_current(&self, def: &Definition) -> bool {
+        std::ptr::eq(&*self.current, def)
+    }
+    #[cfg(feature = "lockfree")]
+    fn is_current(&self, def: &Definition) -> bool {
+        std::ptr::eq(&**self.current.load(), def)
+    }
+    // the current definition, then the history from most recent, then the default
+    // definition if it wasn't in either. The default's stats are only listed once.
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> Vec<CallStats> {
+        let current = self.current();
+        let mut stats: Vec<CallStats> = vec![current.stats.snapshot(current.state.clone(), true)];
+        let mut default_seen = Arc::ptr_eq(&current.stats, &self.default_stats);
+        for def in self.history.iter().rev() {
+            if Arc::ptr_eq(&def.stats, &self.default_stats) {
+                if default_seen {
+                    continue;
+                }
+                default_seen = true;
+            }
+            stats.push(def.stats.snapshot(def.state.clone(), false));
+        }
+        if !default_seen {
+            stats.push(self.default_stats.snapshot(PatchState::Default, false));
+        }
+        stats
+    }
+}
+
+// Every change to the internals is a single swap, so a panic while they were locked
+// can't have left them half-updated. A poisoned lock is used as it is, and stays poisoned.
+impl<RealType: ?Sized + Send + Sync + 'static> Patchable<RealType> {
+    fn lock(&self) -> &Lock<HotpatchImportInternal<RealType>> {
+        self.lazy.as_ref().unwrap()
+    }
+    // identifies this Patchable in thread-local overrides; it is a static, so never moves
+    fn key(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, HotpatchImportInternal<RealType>> {
+        self.lock().read().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn write(&self) -> RwLockWriteGuard<'_, HotpatchImportInternal<RealType>> {
+        // the path is behind the lock, so contention is reported once it's taken
+        #[cfg(feature = "tracing")]
+        let waiting = match self.try_write() {
+            Err(HotpatchError::WouldBlock) => std::time::Instant::now(),
+            guard => return guard.unwrap(), // only fails with WouldBlock
+        };
+        let guard = self.lock().write().unwrap_or_else(PoisonError::into_inner);
+        event!(DEBUG, patchable = guard.path, waited = ?waiting.elapsed(), "waited for a contended lock");
+        guard
+    }
+    // run op on the internals without taking the lock, see force_restore_default
+    unsafe fn force<R>(&self, op: impl FnOnce(&mut HotpatchImportInternal<RealType>) -> R) -> R {
+        let sref = self as *const Self as *mut Self;
+        let mut rref = (*sref).lazy.take().unwrap();
+        let inner = rref.get_mut().unwrap_or_else(PoisonError::into_inner);
+        event!(WARN, patchable = inner.path, "forcing a hotpatch without the lock");
+        let reslt = op(inner);
+        *(*sref).lazy = Some(rref);
+        reslt
+    }
+    pub(crate) fn try_write(
+        &self,
+    ) -> Result<RwLockWriteGuard<'_, HotpatchImportInternal<RealType>>, HotpatchError> {
+        match self.lock().try_write() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::WouldBlock) => Err(HotpatchError::WouldBlock),
+            Err(TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+        }
     }
 }
 
 // passthrough methods
 impl<RealType: ?Sized + Send + Sync + 'static> Patchable<RealType> {
     #[doc(hidden)]
-    pub const fn __new(ptr: fn() -> Option<RwLock<HotpatchImportInternal<RealType>>>) -> Self {
+    pub const fn __new(ptr: fn() -> Option<Lock<HotpatchImportInternal<RealType>>>) -> Self {
         Self {
             lazy: Lazy::new(ptr),
         }
@@ -203,33 +903,38 @@ impl<RealType: ?Sized + Send + Sync + 'static> Patchable<RealType> {
         ptr: T,
         mpath: &'static str,
         sig: &'static str,
-    ) -> Option<RwLock<HotpatchImportInternal<RealType>>> {
-        Some(RwLock::new(HotpatchImportInternal::new(ptr, mpath, sig)))
+        abi: u64,
+        on_panic: OnPanic,
+    ) -> Option<Lock<HotpatchImportInternal<RealType>>> {
+        Some(Lock::new(HotpatchImportInternal::new(
+            ptr, mpath, sig, abi, on_panic,
+        )))
     }
 
     /// Hotpatch this functor back to its original definition.
     ///
     /// ## Example
-    /// ```
+    /// ```no_run
+    /// # use hotpatch::*;
     /// #[patchable]
     /// fn foo() {}
     ///
-    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// fn main() -> Result<(), HotpatchError> {
     ///   foo(); // does A
     ///   foo.hotpatch_lib("libtest.so")?;
     ///   foo(); // does B
-    ///   foo.restore_default();
+    ///   foo.restore_default()?;
     ///   foo(); // does A again
     ///   Ok(())
     /// }
     /// ```
-    pub fn restore_default(&self) -> Result<(), Box<dyn std::error::Error + '_>> {
-        self.lazy.as_ref().unwrap().write()?.restore_default()
+    pub fn restore_default(&self) -> Result<(), HotpatchError> {
+        self.write().restore_default()
     }
     /// Like [`restore_default`](Patchable::restore_default) but uses
     /// [`RwLock::try_write`](https://doc.rust-lang.org/std/sync/struct.RwLock.html#method.try_write).
-    pub fn try_restore_default(&self) -> Result<(), Box<dyn std::error::Error + '_>> {
-        self.lazy.as_ref().unwrap().try_write()?.restore_default()
+    pub fn try_restore_default(&self) -> Result<(), HotpatchError> {
+        self.try_write()?.restore_default()
     }
     /// Like [`restore_default`](Patchable::restore_default) but uses
     /// unsafe features to completly bypass the
@@ -245,17 +950,75 @@ impl<RealType: ?Sized + Send + Sync + 'static> Patchable<RealType> {
     /// if a thread tries to call a `Patchable` during a (small but nonzero duration) `force` transition.
     ///
     /// **Use with caution**.
-    pub unsafe fn force_restore_default(&self) -> Result<(), Box<dyn std::error::Error + '_>> {
-        let sref = self as *const Self as *mut Self;
-        let mut rref = (*sref).lazy.take().unwrap();
-        let reslt = rref.get_mut().unwrap().restore_default();
-        *(*sref).lazy = Some(rref);
-        reslt
+    pub unsafe fn force_restore_default(&self) -> Result<(), HotpatchError> {
+        self.force(|inner| inner.restore_default())
+    }
+    /// Hotpatch this functor back to the definition it had before the last hotpatch,
+    /// whether that was the original, a closure, or from a library.
+    ///
+    /// Each [`Patchable`](Patchable) keeps a history of previous definitions, up to the limit
+    /// set by [`set_history_limit`](set_history_limit). Every hotpatch, including
+    /// [`restore_default`](Patchable::restore_default), adds to it, and every rollback takes
+    /// one off. Fails with [`HotpatchError::NoHistory`](HotpatchError::NoHistory) once
+    /// there's nothing left to roll back to.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// # use hotpatch::*;
+    /// #[patchable]
+    /// fn foo() {}
+    ///
+    /// fn main() -> Result<(), HotpatchError> {
+    ///   foo.hotpatch_lib("libgood.so")?;
+    ///   foo.hotpatch_lib("libbad.so")?;
+    ///   foo.rollback()?;
+    ///   foo(); // runs the definition from libgood.so
+    ///   Ok(())
+    /// }
+    /// ```
+    pub fn rollback(&self) -> Result<(), HotpatchError> {
+        self.write().rollback()
+    }
+    /// Like [`rollback`](Patchable::rollback) but uses
+    /// [`RwLock::try_write`](https://doc.rust-lang.org/std/sync/struct.RwLock.html#method.try_write).
+    pub fn try_rollback(&self) -> Result<(), HotpatchError> {
+        self.try_write()?.rollback()
+    }
+    /// Like [`rollback`](Patchable::rollback) but uses
+    /// unsafe features to completly bypass the
+    /// [`RwLock`](https://doc.rust-lang.org/std/sync/struct.RwLock.html).
+    ///
+    /// # Safety
+    /// See [`force_restore_default`](Patchable::force_restore_default).
+    pub unsafe fn force_rollback(&self) -> Result<(), HotpatchError> {
+        self.force(|inner| inner.rollback())
+    }
+    /// Take the outermost wrapper added by [`wrap`](HotpatchWrap::wrap) or a
+    /// [`#[patch(wrap)]`](patch) export off the current definition.
+    ///
+    /// Like any other hotpatch, this can be undone with [`rollback`](Patchable::rollback).
+    /// Fails with [`HotpatchError::NotWrapped`](HotpatchError::NotWrapped) if the current
+    /// definition isn't a wrapper.
+    pub fn pop_wrapper(&self) -> Result<(), HotpatchError> {
+        self.write().unwrap(None)
+    }
+    /// Take one wrapper out of the current definition, wherever it is in the stack. The
+    /// wrappers above it are put back over the one below.
+    ///
+    /// Fails with [`HotpatchError::NotWrapped`](HotpatchError::NotWrapped) if the wrapper
+    /// isn't part of the current definition, such as after it's been replaced by
+    /// [`hotpatch_fn`](Patchable::hotpatch_fn).
+    pub fn remove_wrapper(&self, id: WrapId) -> Result<(), HotpatchError> {
+        self.write().unwrap(Some(id))
+    }
+    /// Remove the check set by [`set_health_check`](HealthCheck::set_health_check).
+    pub fn clear_health_check(&self) {
+        self.write().set_health(None)
     }
 }
 
 trait HotpatchFnInternal<T, Dummy> {
-    unsafe fn hotpatch_fn(&mut self, c: T) -> Result<(), Box<dyn std::error::Error>>;
+    unsafe fn hotpatch_fn(&mut self, c: T) -> Result<(), HotpatchError>;
 }
 
 #[cfg(not(doc))]
@@ -266,17 +1029,23 @@ va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
         T: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
         RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
         {
-            unsafe fn hotpatch_fn(&mut self, c: T) -> Result<(), Box<dyn std::error::Error>> {
+            unsafe fn hotpatch_fn(&mut self, c: T) -> Result<(), HotpatchError> {
             let boxed: Box<T> = Box::new(c);
             let reboxed: Box<dyn Fn($($va_idents,)*) -> Ret> = boxed;
             let dbox: Box<FnVoid> = std::mem::transmute(reboxed);
-            self.current_ptr = dbox;
-            self.clean()
+            self.replace(dbox, PatchState::Closure)
             }
         }
 }
-trait HotpatchLibInternal<Dummy> {
-    fn hotpatch_lib(&mut self, lib_name: &str) -> Result<(), Box<dyn std::error::Error>>;
+#[doc(hidden)]
+pub trait HotpatchLibInternal<Dummy> {
+    /// Find and check the export for this function in an already loaded library.
+    fn definition(
+        &self,
+        lib: &Arc<LoadedLibrary>,
+        lib_name: &str,
+    ) -> Result<Definition, HotpatchError>;
+    fn hotpatch_lib(&mut self, lib_name: &str) -> Result<(), HotpatchError>;
 }
 
 #[cfg(not(doc))]
@@ -286,37 +1055,77 @@ va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
 where
     RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
 {
-    fn hotpatch_lib(&mut self, lib_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    fn definition(
+        &self,
+        lib: &Arc<LoadedLibrary>,
+        lib_name: &str,
+    ) -> Result<Definition, HotpatchError> {
+        let _span = span!(DEBUG, "resolve", patchable = self.path, lib = lib_name);
         unsafe {
-            let lib = libloading::Library::new(lib_name)?;
-
-            let mut i: usize = 0;
-
-            loop {
-                let symbol_name = format!("{}{}", "__HOTPATCH_EXPORT_", i);
-                let exports: libloading::Symbol<*mut HotpatchExport<fn($($va_idents,)*) -> Ret>> =
-                    lib.get(symbol_name.as_bytes()).map_err(|_| {
-                        format!(
-                            "Hotpatch for {} failed: symbol not found in library {}",
-                            self.mpath, lib_name
-                        )
-                    })?;
-                let export_obj = &**exports;
-                if export_obj.symbol.trim_start_matches(|c| c != ':') == self.mpath {
-                    // found the correct symbol
-                    if self.sig != export_obj.sig {
-                        bail!("Hotpatch for {} failed: symbol found but of wrong type. Expected {} but found {}", self.mpath, self.sig, export_obj.sig);
+            let ptr = {
+                let index = ManifestIndex::read(lib, lib_name)?;
+                let entry = match index.get(self.mpath) {
+                    Some(entry) => entry,
+                    None => {
+                        event!(WARN, symbol = self.mpath, "no export for this patchable");
+                        return Err(HotpatchError::SymbolMissing {
+                            symbol: self.mpath.to_owned(),
+                            lib: lib_name.to_owned(),
+                            available: index.names().into_iter().map(String::from).collect(),
+                        })
                     }
-                    let d: Box<fn($($va_idents,)*) -> Ret> = Box::new(export_obj.ptr);
-                    let t: Box<dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static> = d;
-                    self.current_ptr = transmute(t);
-                    self.clean()?;
-                    self.lib = Some(lib);
-                    break;
+                };
+                if self.sig != entry.sig {
+                    event!(WARN, expected = self.sig, found = entry.sig, "signature mismatch");
+                    return Err(HotpatchError::SignatureMismatch {
+                        symbol: self.mpath.to_owned(),
+                        expected: self.sig.to_owned(),
+                        found: entry.sig.to_owned(),
+                    });
                 }
-                i += 1;
-            }
+                if self.abi != (entry.abi)() {
+                    event!(WARN, sig = self.sig, "layout mismatch");
+                    return Err(HotpatchError::LayoutMismatch {
+                        symbol: self.mpath.to_owned(),
+                        sig: self.sig.to_owned(),
+                    });
+                }
+                event!(DEBUG, symbol = entry.symbol, wrap = entry.wrap, original = entry.original.is_some(), "resolved export");
+                if let Some(original) = entry.original {
+                    // see Self::new for why this lives as long as the Patchable
+                    original.__set(&*(&*self.default_ptr as *const FnVoid));
+                }
+                if entry.wrap {
+                    let export_obj = entry.export::<fn(&(dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static), $($va_idents,)*) -> Ret>();
+                    let wrapper = Wrapper::new(
+                        export_obj.ptr,
+                        |layer| transmute::<Box<dyn Fn($($va_idents,)*) -> Ret + Send + Sync>, Box<FnVoid>>(Box::new(layer)),
+                        PatchState::Library(lib_name.to_owned()),
+                        Some(lib.clone()),
+                        Some(entry.symbol.to_owned()),
+                    );
+                    // over the current definition for now, see push
+                    return Ok(wrapper.over(self.current()));
+                }
+                let export_obj = entry.export::<fn($($va_idents,)*) -> Ret>();
+                let d: Box<fn($($va_idents,)*) -> Ret> = Box::new(export_obj.ptr);
+                let t: Box<dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static> = d;
+                transmute(t)
+            };
+            Ok(Definition {
+                ptr,
+                lib: Some(lib.clone()),
+                state: PatchState::Library(lib_name.to_owned()),
+                wrap: None,
+                #[cfg(feature = "stats")]
+                stats: Default::default(),
+            })
         }
+    }
+    fn hotpatch_lib(&mut self, lib_name: &str) -> Result<(), HotpatchError> {
+        let lib = Arc::new(load_library(lib_name)?);
+        let def = self.definition(&lib, lib_name)?;
+        self.push(def.into());
         Ok(())
     }
 }
@@ -324,13 +1133,13 @@ where
 
 /// Public interface for [Patchable::hotpatch_lib] and associated; requires import to use.
 pub trait HotpatchLib<Dummy> {
-    fn hotpatch_lib(&self, lib_name: &str) -> Result<(), Box<dyn std::error::Error + '_>>;
-    fn try_hotpatch_lib(&self, lib_name: &str) -> Result<(), Box<dyn std::error::Error + '_>>;
+    fn hotpatch_lib(&self, lib_name: &str) -> Result<(), HotpatchError>;
+    fn try_hotpatch_lib(&self, lib_name: &str) -> Result<(), HotpatchError>;
     #[allow(clippy::missing_safety_doc)] // documentation is elsewhere and linked to
     unsafe fn force_hotpatch_lib(
         &self,
         lib_name: &str,
-    ) -> Result<(), Box<dyn std::error::Error + '_>>;
+    ) -> Result<(), HotpatchError>;
 }
 
 #[cfg(not(doc))]
@@ -341,35 +1150,27 @@ va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
         RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
 
         {
-    fn hotpatch_lib(&self, lib_name: &str) -> Result<(), Box<dyn std::error::Error + '_>> {
-        self.lazy.as_ref().unwrap().write()?.hotpatch_lib(lib_name)
+    fn hotpatch_lib(&self, lib_name: &str) -> Result<(), HotpatchError> {
+        self.write().hotpatch_lib(lib_name)
     }
-    fn try_hotpatch_lib(&self, lib_name: &str) -> Result<(), Box<dyn std::error::Error + '_>> {
-        self.lazy
-            .as_ref()
-            .unwrap()
-            .try_write()?
-            .hotpatch_lib(lib_name)
+    fn try_hotpatch_lib(&self, lib_name: &str) -> Result<(), HotpatchError> {
+        self.try_write()?.hotpatch_lib(lib_name)
     }
     unsafe fn force_hotpatch_lib(
         &self,
         lib_name: &str,
-    ) -> Result<(), Box<dyn std::error::Error + '_>> {
-        let sref = self as *const Self as *mut Self;
-        let mut rref = (*sref).lazy.take().unwrap();
-        let reslt = rref.get_mut().unwrap().hotpatch_lib(lib_name);
-        *(*sref).lazy = Some(rref);
-        reslt
+    ) -> Result<(), HotpatchError> {
+        self.force(|inner| inner.hotpatch_lib(lib_name))
     }
         }
 }
 
 /// Public interface for [Patchable::hotpatch_fn] and associated; requires import to use
 pub trait HotpatchFn<T, Dummy> {
-    fn hotpatch_fn(&self, c: T) -> Result<(), Box<dyn std::error::Error + '_>>;
-    fn try_hotpatch_fn(&self, c: T) -> Result<(), Box<dyn std::error::Error + '_>>;
+    fn hotpatch_fn(&self, c: T) -> Result<(), HotpatchError>;
+    fn try_hotpatch_fn(&self, c: T) -> Result<(), HotpatchError>;
     #[allow(clippy::missing_safety_doc)] // documentation is elsewhere and linked to
-    unsafe fn force_hotpatch_fn(&self, c: T) -> Result<(), Box<dyn std::error::Error + '_>>;
+    unsafe fn force_hotpatch_fn(&self, c: T) -> Result<(), HotpatchError>;
 }
 
 #[cfg(not(doc))]
@@ -380,18 +1181,226 @@ va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
         T: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
         RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
         {
-            fn hotpatch_fn(&self, c: T) -> Result<(), Box<dyn std::error::Error + '_>> {
-            unsafe { self.lazy.as_ref().unwrap().write()?.hotpatch_fn(c) }
+            fn hotpatch_fn(&self, c: T) -> Result<(), HotpatchError> {
+            unsafe { self.write().hotpatch_fn(c) }
             }
-            fn try_hotpatch_fn(&self, c: T) -> Result<(), Box<dyn std::error::Error + '_>> {
-            unsafe { self.lazy.as_ref().unwrap().try_write()?.hotpatch_fn(c) }
+            fn try_hotpatch_fn(&self, c: T) -> Result<(), HotpatchError> {
+            unsafe { self.try_write()?.hotpatch_fn(c) }
             }
-            unsafe fn force_hotpatch_fn(&self, c: T) -> Result<(), Box<dyn std::error::Error + '_>> {
-            let sref = self as *const Self as *mut Self;
-            let mut rref = (*sref).lazy.take().unwrap();
-            let reslt = rref.get_mut().unwrap().hotpatch_fn(c);
-            *(*sref).lazy = Some(rref);
-            reslt
+            unsafe fn force_hotpatch_fn(&self, c: T) -> Result<(), HotpatchError> {
+            self.force(|inner| inner.hotpatch_fn(c))
+            }
+        }
+}
+
+/// Public interface for [`scoped_patch`](HotpatchScoped::scoped_patch); requires import to use.
+pub trait HotpatchScoped<T, Dummy> {
+    /// Like [`hotpatch_fn`](HotpatchFn::hotpatch_fn), but only until the returned guard
+    /// is dropped. The definition that was in place before is then put back, whether it
+    /// was the original, a closure, or from a library.
+    ///
+    /// Guards can be nested. If one is dropped while a later patch is still in place, its
+    /// closure is taken out from underneath, so that dropping the later guard skips it.
+    ///
+    /// ## Example
+    /// ```
+    /// # use hotpatch::*;
+    /// #[patchable]
+    /// fn foo() -> i32 { 1 }
+    ///
+    /// fn main() -> Result<(), HotpatchError> {
+    ///   {
+    ///     let _g = foo.scoped_patch(|| 2)?;
+    ///     assert_eq!(foo(), 2);
+    ///     {
+    ///       let _g = foo.scoped_patch(|| 3)?;
+    ///       assert_eq!(foo(), 3);
+    ///     }
+    ///     assert_eq!(foo(), 2);
+    ///   }
+    ///   assert_eq!(foo(), 1);
+    ///   Ok(())
+    /// }
+    /// ```
+    fn scoped_patch(&self, c: T) -> Result<ScopedPatch<'_>, HotpatchError>;
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        impl<RealType: ?Sized + Send + Sync + 'static, T, Ret, $($va_idents,)*> HotpatchScoped<T, (Ret, $($va_idents,)*)>
+        for Patchable<RealType>
+    where
+        T: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        {
+            fn scoped_patch(&self, c: T) -> Result<ScopedPatch<'_>, HotpatchError> {
+            let boxed: Box<T> = Box::new(c);
+            let reboxed: Box<dyn Fn($($va_idents,)*) -> Ret> = boxed;
+            let installed: SharedDefinition = Definition {
+                ptr: unsafe { transmute(reboxed) },
+                lib: None,
+                state: PatchState::Closure,
+                wrap: None,
+                #[cfg(feature = "stats")]
+                stats: Default::default(),
+            }
+            .into();
+            let previous = self.write().push(installed.clone());
+            Ok(ScopedPatch::new(self, installed, previous))
+            }
+        }
+}
+
+/// Public interface for [`patch_thread_local`](HotpatchLocal::patch_thread_local); requires import to use.
+pub trait HotpatchLocal<T, Dummy> {
+    /// Override the function on this thread only, until the returned guard is dropped.
+    /// Other threads keep calling the global definition, so parallel tests can each mock
+    /// the same function.
+    ///
+    /// Overrides are checked before the global definition and shadow it completely,
+    /// including any health check or `catch_panic`. Nested overrides shadow each other
+    /// like [`scoped_patch`](HotpatchScoped::scoped_patch) guards. Threads don't start with
+    /// the overrides of the thread that spawned them; see [`LocalOverrides`](LocalOverrides)
+    /// and [`spawn_with_overrides`](spawn_with_overrides).
+    ///
+    /// ## Example
+    /// ```
+    /// # use hotpatch::*;
+    /// #[patchable]
+    /// fn foo() -> i32 { 1 }
+    ///
+    /// fn mocked() {
+    ///   let _g = foo.patch_thread_local(|| 2);
+    ///   assert_eq!(foo(), 2);
+    ///   assert_eq!(std::thread::spawn(|| foo()).join().unwrap(), 1);
+    ///   assert_eq!(hotpatch::spawn_with_overrides(|| foo()).join().unwrap(), 2);
+    /// }
+    /// # fn main() { mocked() }
+    /// ```
+    fn patch_thread_local(&self, c: T) -> LocalPatch;
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        impl<RealType: ?Sized + Send + Sync + 'static, T, Ret, $($va_idents,)*> HotpatchLocal<T, (Ret, $($va_idents,)*)>
+        for Patchable<RealType>
+    where
+        T: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        {
+            fn patch_thread_local(&self, c: T) -> LocalPatch {
+            let boxed: Box<T> = Box::new(c);
+            let reboxed: Box<dyn Fn($($va_idents,)*) -> Ret> = boxed;
+            let def = Definition {
+                ptr: unsafe { transmute(reboxed) },
+                lib: None,
+                state: PatchState::Closure,
+                wrap: None,
+                #[cfg(feature = "stats")]
+                stats: Default::default(),
+            };
+            LocalPatch::new(self.key(), def.into())
+            }
+        }
+}
+
+/// Public interface for [`wrap`](HotpatchWrap::wrap); requires import to use.
+pub trait HotpatchWrap<W, Dummy> {
+    /// Hotpatch this functor with `wrapper`, which is called with the definition that was
+    /// current and the arguements, and can call through to it.
+    ///
+    /// Wrappers stack: each goes over whatever was current, including other wrappers.
+    /// Any of them can be taken back out with [`remove_wrapper`](Patchable::remove_wrapper)
+    /// using the returned [`WrapId`](WrapId), or the outermost with
+    /// [`pop_wrapper`](Patchable::pop_wrapper), leaving the rest in place. Libraries can
+    /// export wrappers too, with [`#[patch(wrap)]`](patch).
+    ///
+    /// ## Example
+    /// ```
+    /// # use hotpatch::*;
+    /// #[patchable]
+    /// fn foo(a: i32) -> i32 { a }
+    ///
+    /// fn main() -> Result<(), HotpatchError> {
+    ///   let logged = foo.wrap(|prev, a| {
+    ///     println!("foo({})", a);
+    ///     prev(a)
+    ///   })?;
+    ///   foo.wrap(|prev, a| prev(a) + 1)?;
+    ///   assert_eq!(foo(1), 2); // prints foo(1)
+    ///   foo.remove_wrapper(logged)?;
+    ///   assert_eq!(foo(1), 2); // prints nothing
+    ///   Ok(())
+    /// }
+    /// ```
+    fn wrap(&self, wrapper: W) -> Result<WrapId, HotpatchError>;
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        impl<RealType: ?Sized + Send + Sync + 'static, W, Ret: 'static, $($va_idents: 'static,)*> HotpatchWrap<W, (Ret, $($va_idents,)*)>
+        for Patchable<RealType>
+    where
+        W: Fn(&(dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static), $($va_idents,)*) -> Ret + Send + Sync + 'static,
+        RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        {
+            fn wrap(&self, wrapper: W) -> Result<WrapId, HotpatchError> {
+            let wrapper = Wrapper::new(
+                wrapper,
+                |layer| unsafe {
+                    transmute::<Box<dyn Fn($($va_idents,)*) -> Ret + Send + Sync>, Box<FnVoid>>(Box::new(layer))
+                },
+                PatchState::Closure,
+                None,
+                None,
+            );
+            let mut inner = self.write();
+            let def = wrapper.over(inner.current());
+            inner.push(def.into());
+            Ok(wrapper.id())
+            }
+        }
+}
+
+/// Public interface for [`set_health_check`](HealthCheck::set_health_check); requires import to use.
+pub trait HealthCheck<F, Dummy> {
+    /// Check every value returned by a hotpatched definition, and [`rollback`](Patchable::rollback)
+    /// once `threshold` calls in a row have failed the check or panicked.
+    ///
+    /// The count starts again whenever the definition changes. The original definition
+    /// isn't checked, as there's nothing to roll back to. As with `catch_panic(revert)`,
+    /// a rollback that would have to wait for the lock is put off until the next failure.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// # use hotpatch::*;
+    /// #[patchable]
+    /// fn parse(s: &str) -> Result<i32, std::num::ParseIntError> {
+    ///   s.parse()
+    /// }
+    ///
+    /// fn main() -> Result<(), HotpatchError> {
+    ///   parse.set_health_check(3, |r: &Result<i32, _>| r.is_ok())?;
+    ///   parse.hotpatch_lib("libparse.so")?; // rolled back after 3 errors in a row
+    ///   Ok(())
+    /// }
+    /// ```
+    fn set_health_check(&self, threshold: usize, check: F) -> Result<(), HotpatchError>;
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        impl<RealType: ?Sized + Send + Sync + 'static, F, Ret, $($va_idents,)*> HealthCheck<F, (Ret, $($va_idents,)*)>
+        for Patchable<RealType>
+    where
+        F: Fn(&Ret) -> bool + Send + Sync + 'static,
+        RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        {
+            fn set_health_check(&self, threshold: usize, check: F) -> Result<(), HotpatchError> {
+            let boxed: Box<dyn Fn(&Ret) -> bool + Send + Sync> = Box::new(check);
+            let health = unsafe { Health::new(transmute(boxed), threshold) };
+            self.write().set_health(Some(health));
+            Ok(())
             }
         }
 }
@@ -405,8 +1414,7 @@ va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
                 {
                 type Output = Ret;
                     extern "rust-call" fn call_once(self, args: ($($va_idents,)*)) -> Ret {
-            let inner = self.lazy.as_ref().unwrap().read().unwrap();
-                    inner.upcast_self().call(args)
+                    self.call(args)
                 }
                 }
 }
@@ -417,8 +1425,7 @@ va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
                 RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
                 {
                 extern "rust-call" fn call_mut(&mut self, args: ($($va_idents,)*)) -> Ret {
-                    let inner = self.lazy.as_ref().unwrap().read().unwrap();
-                    inner.upcast_self().call(args)
+                    self.call(args)
                 }
                 }
 }
@@ -428,9 +1435,61 @@ va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
     where
                 RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
                 {
+                #[cfg(not(feature = "lockfree"))]
+                extern "rust-call" fn call(&self, args: ($($va_idents,)*)) -> Ret {
+                    if let Some(local) = local::get(self.key()) {
+                        return local.upcast::<RealType>().call(args);
+                    }
+                    let inner = self.read();
+                    // the lock doesn't protect against force swaps, this does
+                    let current = inner.current.clone();
+                    let health = inner.health.clone();
+                    if !guarded(inner.on_panic, health.as_deref(), &current) {
+                        #[cfg(feature = "stats")]
+                        let timer = current.stats.start();
+                        let ret = current.upcast::<RealType>().call(args);
+                        #[cfg(feature = "stats")]
+                        timer.stop();
+                        return ret;
+                    }
+                    let (path, on_panic) = (inner.path, inner.on_panic);
+                    let result = catch_unwind(AssertUnwindSafe(|| {
+                        #[cfg(feature = "stats")]
+                        let timer = current.stats.start();
+                        let ret = current.upcast::<RealType>().call(args);
+                        #[cfg(feature = "stats")]
+                        timer.stop();
+                        ret
+                    }));
+                    // reverting or rolling back needs the write lock
+                    drop(inner);
+                    checked(self.lock(), path, on_panic, health.as_deref(), &current, result)
+                }
+                #[cfg(feature = "lockfree")]
                 extern "rust-call" fn call(&self, args: ($($va_idents,)*)) -> Ret {
-                    let inner = self.lazy.as_ref().unwrap().read().unwrap();
-                    inner.upcast_self().call(args)
+                    if let Some(local) = local::get(self.key()) {
+                        return local.upcast::<RealType>().call(args);
+                    }
+                    let lock = self.lock();
+                    let current = lock.load();
+                    let health = lock.health.load();
+                    if !guarded(lock.on_panic, health.as_deref(), &current) {
+                        #[cfg(feature = "stats")]
+                        let timer = current.stats.start();
+                        let ret = current.upcast::<RealType>().call(args);
+                        #[cfg(feature = "stats")]
+                        timer.stop();
+                        return ret;
+                    }
+                    let result = catch_unwind(AssertUnwindSafe(|| {
+                        #[cfg(feature = "stats")]
+                        let timer = current.stats.start();
+                        let ret = current.upcast::<RealType>().call(args);
+                        #[cfg(feature = "stats")]
+                        timer.stop();
+                        ret
+                    }));
+                    checked(lock, lock.path, lock.on_panic, health.as_deref(), &current, result)
                 }
                 }
 }
@@ -450,3 +1509,132 @@ impl<T> std::ops::Deref for MutConst<T> {
         (self.f)()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    struct Account {
+        balance: i64,
+    }
+
+    #[patchable]
+    impl Account {
+        fn balance(&self) -> i64 {
+            self.balance
+        }
+        fn deposit(&mut self, amount: i64) {
+            self.balance += amount;
+        }
+        fn close(self) -> i64 {
+            self.balance
+        }
+    }
+
+    #[test]
+    fn methods_with_receivers() {
+        let mut account = Account { balance: 10 };
+        account.deposit(5);
+        assert_eq!(account.balance(), 15);
+        {
+            let _fee = Account::deposit_patchable
+                .scoped_patch(|a: &mut Account, amount: i64| a.balance += amount - 1)
+                .unwrap();
+            let _doubled = Account::balance_patchable
+                .scoped_patch(|a: &Account| a.balance * 2)
+                .unwrap();
+            account.deposit(5);
+            assert_eq!(account.balance(), 38);
+        }
+        assert_eq!(account.balance(), 19);
+        assert_eq!(account.close(), 19);
+    }
+
+    struct Counter {
+        n: u32,
+    }
+
+    #[patchable]
+    impl Iterator for Counter {
+        type Item = u32;
+        fn next(&mut self) -> Option<Self::Item> {
+            self.n += 1;
+            Some(self.n)
+        }
+    }
+
+    #[test]
+    fn trait_impl_with_associated_type() {
+        let mut counter = Counter { n: 0 };
+        assert_eq!(counter.next(), Some(1));
+        {
+            let _done = Counter::iterator_next_patchable
+                .scoped_patch(|_: &mut Counter| -> Option<u32> { None })
+                .unwrap();
+            assert_eq!(counter.next(), None);
+            // calls through the trait reach the patch too
+            assert_eq!((&mut counter as &mut dyn Iterator<Item = u32>).next(), None);
+        }
+        assert_eq!(counter.next(), Some(2));
+    }
+
+    #[patchable]
+    async fn greet(name: String) -> String {
+        format!("hello {}", name)
+    }
+
+    // the futures here never wait, so polling once is enough
+    fn ready<F: std::future::Future>(f: F) -> F::Output {
+        use std::task::{Context, Poll, Waker};
+        match std::pin::pin!(f).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(out) => out,
+            Poll::Pending => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn async_functions_return_boxed_futures() {
+        assert_eq!(ready(greet("a".to_owned())), "hello a");
+        let _shout = greet
+            .scoped_patch(|name: String| -> BoxFuture<String> {
+                Box::pin(async move { format!("HELLO {}", name.to_uppercase()) })
+            })
+            .unwrap();
+        assert_eq!(ready(greet("a".to_owned())), "HELLO A");
+        let entry = registry().get("hotpatch::tests::greet").unwrap();
+        assert_eq!(entry.sig(), "async fn(String) -> String");
+    }
+
+    #[patchable(instantiate(T = u32, T = String))]
+    fn parse<T: std::str::FromStr + Default>(s: &str) -> T {
+        s.parse().unwrap_or_default()
+    }
+
+    #[test]
+    fn each_instantiation_is_patched_on_its_own() {
+        assert_eq!(parse::<u32>("12"), 12);
+        let _len = parse::u32.scoped_patch(|s: &str| s.len() as u32).unwrap();
+        assert_eq!(parse::<u32>("12"), 2);
+        assert_eq!(parse::<String>("12"), "12");
+        let registered: Vec<_> = registry().iter().map(|e| e.path()).collect();
+        assert!(registered.contains(&"hotpatch::tests::parse::<u32>"), "{:?}", registered);
+    }
+
+    #[patch]
+    fn exported() -> &'static str {
+        "exported"
+    }
+
+    #[patch(exported)]
+    fn exported_by_key() -> &'static str {
+        "exported_by_key"
+    }
+
+    #[test]
+    fn exports_with_the_same_key_and_signature() {
+        let exported: Vec<_> = HOTPATCH_EXPORTS.iter().map(|e| e.symbol).collect();
+        assert!(exported.contains(&"hotpatch::tests::exported"));
+        assert!(exported.contains(&"::exported"));
+        assert_eq!(exported_by_key(), "exported_by_key");
+    }
+}
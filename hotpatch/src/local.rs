@@ -0,0 +1,225 @@
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+
+use crate::SharedDefinition;
+
+// Overrides on every thread, so calls can skip the thread local while there are none
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    id: u64,
+    key: usize, // address of the Patchable
+    def: SharedDefinition,
+    inherit: bool,
+}
+
+thread_local! {
+    // most recent last, so nested overrides of one function shadow each other
+    #[allow(clippy::missing_const_for_thread_local)]
+    static LOCAL: RefCell<Vec<Entry>> = RefCell::new(Vec::new());
+}
+
+pub(crate) fn get(key: usize) -> Option<SharedDefinition> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    LOCAL
+        .try_with(|local| {
+            let local = local.borrow();
+            local.iter().rev().find(|e| e.key == key).map(|e| e.def.clone())
+        })
+        .ok()
+        .flatten()
+}
+
+fn insert(key: usize, def: SharedDefinition, inherit: bool) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    LOCAL.with(|local| {
+        local.borrow_mut().push(Entry {
+            id,
+            key,
+            def,
+            inherit,
+        })
+    });
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    id
+}
+
+fn remove(id: u64) {
+    // the thread local may already be gone if this runs as the thread exits
+    let removed = LOCAL
+        .try_with(|local| {
+            let mut local = local.borrow_mut();
+            let i = local.iter().position(|e| e.id == id)?;
+            Some(local.remove(i))
+        })
+        .ok()
+        .flatten();
+    if removed.is_some() {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returned by [`patch_thread_local`](crate::HotpatchLocal::patch_thread_local). Removes
+/// the override when dropped.
+///
+/// Overrides belong to the thread that made them, so this can't be sent to another thread.
+#[must_use = "the override is removed as soon as the guard is dropped"]
+pub struct LocalPatch {
+    id: u64,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl LocalPatch {
+    pub(crate) fn new(key: usize, def: SharedDefinition) -> Self {
+        Self {
+            id: insert(key, def, true),
+            _not_send: PhantomData,
+        }
+    }
+    /// Choose whether [`LocalOverrides::capture`](LocalOverrides::capture) and
+    /// [`spawn_with_overrides`](spawn_with_overrides) pass this override on to other
+    /// threads. They do by default.
+    pub fn inherit(self, inherit: bool) -> Self {
+        LOCAL.with(|local| {
+            if let Some(e) = local.borrow_mut().iter_mut().find(|e| e.id == self.id) {
+                e.inherit = inherit;
+            }
+        });
+        self
+    }
+}
+
+impl Drop for LocalPatch {
+    fn drop(&mut self) {
+        remove(self.id);
+    }
+}
+
+/// The thread-local overrides of one thread, to be used on another.
+///
+/// Threads don't start with any overrides, so ones spawned by the code under test run the
+/// real definitions. Capture the overrides first and apply them on the new thread, or use
+/// [`spawn_with_overrides`](spawn_with_overrides) to do both.
+///
+/// ## Example
+/// ```
+/// # use hotpatch::*;
+/// # #[patchable]
+/// # fn foo() -> i32 { 1 }
+/// # fn main() {
+/// let _mock = foo.patch_thread_local(|| 2);
+/// let overrides = hotpatch::LocalOverrides::capture();
+/// std::thread::spawn(move || overrides.apply(|| assert_eq!(foo(), 2)))
+///     .join()
+///     .unwrap();
+/// # }
+/// ```
+pub struct LocalOverrides {
+    entries: Vec<(usize, SharedDefinition)>, // oldest first
+}
+
+impl LocalOverrides {
+    /// Take a copy of every override on this thread that is inherited.
+    pub fn capture() -> Self {
+        let entries = LOCAL
+            .try_with(|local| {
+                local
+                    .borrow()
+                    .iter()
+                    .filter(|e| e.inherit)
+                    .map(|e| (e.key, e.def.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { entries }
+    }
+    /// Run `f` on this thread with the captured overrides in place, on top of any it
+    /// already has. They're removed again when `f` returns or panics.
+    pub fn apply<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        let _patches: Vec<LocalPatch> = self
+            .entries
+            .iter()
+            .map(|(key, def)| LocalPatch::new(*key, def.clone()))
+            .collect();
+        f()
+    }
+}
+
+/// Like [`std::thread::spawn`](std::thread::spawn), but the new thread starts with the
+/// inherited thread-local overrides of this one.
+pub fn spawn_with_overrides<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let overrides = LocalOverrides::capture();
+    std::thread::spawn(move || overrides.apply(f))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[patchable]
+    fn shadowed() -> i32 {
+        0
+    }
+
+    #[test]
+    fn nested_overrides_shadow_each_other() {
+        {
+            let _outer = shadowed.patch_thread_local(|| 1);
+            {
+                let _inner = shadowed.patch_thread_local(|| 2);
+                assert_eq!(shadowed(), 2);
+            }
+            assert_eq!(shadowed(), 1);
+        }
+        assert_eq!(shadowed(), 0);
+    }
+
+    #[patchable]
+    fn per_thread() -> i32 {
+        0
+    }
+
+    #[test]
+    fn other_threads_run_the_global_definition() {
+        let _mock = per_thread.patch_thread_local(|| 1);
+        assert_eq!(per_thread(), 1);
+        assert_eq!(std::thread::spawn(&per_thread).join().unwrap(), 0);
+        assert_eq!(spawn_with_overrides(&per_thread).join().unwrap(), 1);
+    }
+
+    #[patchable]
+    fn not_inherited() -> i32 {
+        0
+    }
+
+    #[test]
+    fn uninherited_overrides_stay_on_their_thread() {
+        let _kept = not_inherited.patch_thread_local(|| 1).inherit(false);
+        assert_eq!(not_inherited(), 1);
+        assert_eq!(spawn_with_overrides(&not_inherited).join().unwrap(), 0);
+    }
+
+    #[patchable]
+    fn under_global() -> i32 {
+        0
+    }
+
+    #[test]
+    fn override_shadows_global_patch() {
+        let _global = under_global.scoped_patch(|| 1).unwrap();
+        {
+            let _mock = under_global.patch_thread_local(|| 2);
+            assert_eq!(under_global(), 2);
+        }
+        assert_eq!(under_global(), 1);
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{Definition, Health, HotpatchImportInternal, OnPanic};
+
+use std::sync::{Arc, LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockResult};
+
+use arc_swap::{ArcSwap, ArcSwapOption, Guard};
+
+/// Stands in for the `RwLock` around a [`Patchable`](crate::Patchable)'s
+/// internals when the `lockfree` feature is enabled.
+///
+/// Patching still goes through the `RwLock`, so patches are serialized with
+/// each other, but calls only load the current definition from an `ArcSwap`
+/// and never touch the lock.
+#[doc(hidden)]
+pub struct Lock<T> {
+    writer: RwLock<T>,
+    current: Arc<ArcSwap<Definition>>,
+    pub(crate) health: Arc<ArcSwapOption<Health>>,
+    // copied from the internals, which calls don't lock
+    pub(crate) path: &'static str,
+    pub(crate) on_panic: OnPanic,
+}
+
+impl<RealType: ?Sized + Send + Sync + 'static> Lock<HotpatchImportInternal<RealType>> {
+    pub(crate) fn new(inner: HotpatchImportInternal<RealType>) -> Self {
+        Self {
+            current: inner.current.clone(),
+            health: inner.health.clone(),
+            path: inner.path,
+            on_panic: inner.on_panic,
+            writer: RwLock::new(inner),
+        }
+    }
+}
+
+impl<T> Lock<T> {
+    pub(crate) fn load(&self) -> Guard<Arc<Definition>> {
+        self.current.load()
+    }
+    pub(crate) fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.writer.read()
+    }
+    pub(crate) fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.writer.write()
+    }
+    pub(crate) fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        self.writer.try_write()
+    }
+    pub(crate) fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.writer.get_mut()
+    }
+}
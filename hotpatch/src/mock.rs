@@ -0,0 +1,568 @@
+//! Call expectations for [`#[patchable]`](crate::patchable) functions in tests.
+//!
+//! [`mock`](HotpatchMock::mock) overrides a function with a [`Mock`](Mock), which checks
+//! each call against the expectations set on it and answers with the first one that
+//! matches. When the mock is dropped, every expectation has to have been called as many
+//! times as it asked for, or the test panics.
+//!
+//! Mocks are [thread-local patches](crate::HotpatchLocal::patch_thread_local), so tests
+//! running in parallel can mock the same function. Threads started with
+//! [`spawn_with_overrides`](crate::spawn_with_overrides) share the mock of the thread that
+//! started them.
+//!
+//! ## Example
+//! ```
+//! # use hotpatch::*;
+//! use hotpatch::mock::*;
+//!
+//! #[patchable]
+//! fn double(a: i32) -> i32 { a * 2 }
+//!
+//! # fn main() { doubles() }
+//! fn doubles() {
+//!     let mut m = double.mock();
+//!     m.expect_call().with(eq(3)).times(2).returning(|_| 7);
+//!     m.expect_call().withf(|&(a,)| a < 0).never();
+//!     assert_eq!(double(3), 7);
+//!     assert_eq!(double(3), 7);
+//! } // m checks that double(3) was called twice
+//! ```
+//! Functions in a [`#[patchable]`](crate::patchable) `impl` block are mocked through their
+//! `Patchable`: `Foo::new.mock()` for an associated function, and
+//! `Foo::into_name_patchable.mock()` for a method, which is only possible if it takes
+//! `self` by value.
+//!
+//! Only functions whose arguments and return type are `'static` can be mocked, since
+//! matchers and return values are free to keep hold of the arguments they're given. A
+//! function taking a reference is better overridden with
+//! [`patch_thread_local`](crate::HotpatchLocal::patch_thread_local).
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use variadic_generics::*;
+
+use crate::{Definition, LocalPatch, PatchState, Patchable};
+
+/// Checks a single argument, for [`with`](Expect::with).
+///
+/// Implemented by closures taking a reference to the argument, as well as the functions
+/// in this module.
+pub trait Predicate<T> {
+    fn eval(&self, arg: &T) -> bool;
+}
+
+impl<T, F: Fn(&T) -> bool> Predicate<T> for F {
+    fn eval(&self, arg: &T) -> bool {
+        self(arg)
+    }
+}
+
+/// Checks every argument of a call, for [`with`](Expect::with).
+///
+/// A function of one argument takes a [`Predicate`](Predicate), and one of more takes a
+/// tuple of them, one per argument.
+pub trait Matcher<Args> {
+    fn matches(&self, args: &Args) -> bool;
+}
+
+impl<A, P: Predicate<A>> Matcher<(A,)> for P {
+    fn matches(&self, args: &(A,)) -> bool {
+        self.eval(&args.0)
+    }
+}
+
+macro_rules! tuple_matcher {
+    ($(($($arg:ident $pred:ident $i:tt),+))*) => {$(
+        impl<$($arg, $pred: Predicate<$arg>,)+> Matcher<($($arg,)+)> for ($($pred,)+) {
+            fn matches(&self, args: &($($arg,)+)) -> bool {
+                $(self.$i.eval(&args.$i))&&+
+            }
+        }
+    )*};
+}
+
+tuple_matcher! {
+    (A PA 0, B PB 1)
+    (A PA 0, B PB 1, C PC 2)
+    (A PA 0, B PB 1, C PC 2, D PD 3)
+    (A PA 0, B PB 1, C PC 2, D PD 3, E PE 4)
+    (A PA 0, B PB 1, C PC 2, D PD 3, E PE 4, F PF 5)
+    (A PA 0, B PB 1, C PC 2, D PD 3, E PE 4, F PF 5, G PG 6)
+    (A PA 0, B PB 1, C PC 2, D PD 3, E PE 4, F PF 5, G PG 6, H PH 7)
+}
+
+/// Compares an argument with a value for equality. Made by [`eq`](eq) and [`ne`](ne).
+pub struct Equals<V> {
+    value: V,
+    equal: bool,
+}
+
+impl<T: PartialEq<V>, V> Predicate<T> for Equals<V> {
+    fn eval(&self, arg: &T) -> bool {
+        (*arg == self.value) == self.equal
+    }
+}
+
+/// Matches an argument equal to `value`.
+pub fn eq<V>(value: V) -> Equals<V> {
+    Equals { value, equal: true }
+}
+
+/// Matches an argument not equal to `value`.
+pub fn ne<V>(value: V) -> Equals<V> {
+    Equals {
+        value,
+        equal: false,
+    }
+}
+
+/// Compares an argument with a value for ordering. Made by [`lt`](lt), [`le`](le),
+/// [`gt`](gt) and [`ge`](ge).
+pub struct Compare<V> {
+    value: V,
+    op: fn(std::cmp::Ordering) -> bool,
+}
+
+impl<T: PartialOrd<V>, V> Predicate<T> for Compare<V> {
+    fn eval(&self, arg: &T) -> bool {
+        matches!(arg.partial_cmp(&self.value), Some(o) if (self.op)(o))
+    }
+}
+
+/// Matches an argument less than `value`.
+pub fn lt<V>(value: V) -> Compare<V> {
+    Compare {
+        value,
+        op: std::cmp::Ordering::is_lt,
+    }
+}
+
+/// Matches an argument less than or equal to `value`.
+pub fn le<V>(value: V) -> Compare<V> {
+    Compare {
+        value,
+        op: std::cmp::Ordering::is_le,
+    }
+}
+
+/// Matches an argument greater than `value`.
+pub fn gt<V>(value: V) -> Compare<V> {
+    Compare {
+        value,
+        op: std::cmp::Ordering::is_gt,
+    }
+}
+
+/// Matches an argument greater than or equal to `value`.
+pub fn ge<V>(value: V) -> Compare<V> {
+    Compare {
+        value,
+        op: std::cmp::Ordering::is_ge,
+    }
+}
+
+/// Matches any argument. Made by [`always`](always).
+pub struct Always;
+
+impl<T> Predicate<T> for Always {
+    fn eval(&self, _: &T) -> bool {
+        true
+    }
+}
+
+/// Matches any argument, to leave it unchecked in a tuple passed to [`with`](Expect::with).
+pub fn always() -> Always {
+    Always
+}
+
+type Returning<Args, Ret> = Box<dyn FnMut(Args) -> Ret + Send>;
+
+struct Expectation<Args, Ret> {
+    matcher: Box<dyn Fn(&Args) -> bool + Send>,
+    min: usize,
+    max: usize,
+    calls: usize,
+    // taken out while it runs, so that it can call the mocked function itself
+    returning: Option<Returning<Args, Ret>>,
+}
+
+struct State<Args, Ret> {
+    path: &'static str,
+    expectations: Vec<Expectation<Args, Ret>>,
+}
+
+type Shared<Args, Ret> = Arc<Mutex<State<Args, Ret>>>;
+
+fn lock<Args, Ret>(state: &Shared<Args, Ret>) -> MutexGuard<'_, State<Args, Ret>> {
+    // a failed expectation panics with the lock held, which leaves nothing half-done
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The definition a mock installs, answering calls from its expectations
+struct Caller<Args, Ret> {
+    state: Shared<Args, Ret>,
+}
+
+impl<Args, Ret> Caller<Args, Ret> {
+    fn answer(&self, args: Args) -> Ret {
+        let mut state = lock(&self.state);
+        let path = state.path;
+        // the earliest match with calls to spare, or one expecting none, noting whether
+        // anything matched at all
+        let mut matched = false;
+        let i = state.expectations.iter().position(|e| {
+            let matches = (e.matcher)(&args);
+            matched |= matches;
+            matches && (e.calls < e.max || e.max == 0)
+        });
+        let i = match i {
+            Some(i) if state.expectations[i].max > 0 => i,
+            _ if matched => panic!("{} was called more times than expected", path),
+            _ => panic!("{} was called with arguments no expectation matches", path),
+        };
+        let expectation = &mut state.expectations[i];
+        expectation.calls += 1;
+        let mut returning = match expectation.returning.take() {
+            Some(returning) => returning,
+            None => panic!("{} was called, but the expectation has no return value", path),
+        };
+        drop(state);
+        let ret = returning(args);
+        lock(&self.state).expectations[i].returning = Some(returning);
+        ret
+    }
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+                impl<Ret, $($va_idents,)*> FnOnce<($($va_idents,)*)> for Caller<($($va_idents,)*), Ret>
+                {
+                type Output = Ret;
+                    extern "rust-call" fn call_once(self, args: ($($va_idents,)*)) -> Ret {
+                    self.answer(args)
+                }
+                }
+}
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+                impl<Ret, $($va_idents,)*> FnMut<($($va_idents,)*)> for Caller<($($va_idents,)*), Ret>
+                {
+                extern "rust-call" fn call_mut(&mut self, args: ($($va_idents,)*)) -> Ret {
+                    self.answer(args)
+                }
+                }
+}
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+                impl<Ret, $($va_idents,)*> Fn<($($va_idents,)*)> for Caller<($($va_idents,)*), Ret>
+                {
+                extern "rust-call" fn call(&self, args: ($($va_idents,)*)) -> Ret {
+                    self.answer(args)
+                }
+                }
+}
+
+/// Returned by [`mock`](HotpatchMock::mock). Overrides the function on this thread until
+/// it's dropped, then checks that every expectation was met.
+#[must_use = "the mock is removed as soon as it's dropped"]
+pub struct Mock<Args, Ret> {
+    state: Shared<Args, Ret>,
+    _patch: LocalPatch,
+}
+
+impl<Args, Ret> Mock<Args, Ret> {
+    /// Add an expectation. Calls are answered by the earliest expectation that matches
+    /// their arguments and hasn't been called as many times as it allows.
+    ///
+    /// An expectation matches any arguments and is expected exactly once unless told
+    /// otherwise. It has to be given something to return, even for a function returning `()`.
+    ///
+    /// The returned [`Expect`](Expect) holds the mock's lock, so calling the function
+    /// while it's still alive will deadlock. Set everything up in one statement.
+    pub fn expect_call(&mut self) -> Expect<'_, Args, Ret> {
+        let mut state = lock(&self.state);
+        state.expectations.push(Expectation {
+            matcher: Box::new(|_| true),
+            min: 1,
+            max: 1,
+            calls: 0,
+            returning: None,
+        });
+        Expect { state }
+    }
+    /// Check that every expectation so far was met, then remove them all.
+    ///
+    /// ## Panics
+    /// If an expectation was called fewer times than it asked for.
+    pub fn checkpoint(&mut self) {
+        let mut state = lock(&self.state);
+        let unmet = unmet(&state);
+        state.expectations.clear();
+        drop(state);
+        if let Some(message) = unmet {
+            panic!("{}", message);
+        }
+    }
+}
+
+fn unmet<Args, Ret>(state: &State<Args, Ret>) -> Option<String> {
+    state.expectations.iter().enumerate().find_map(|(i, e)| {
+        (e.calls < e.min).then(|| {
+            format!(
+                "{}: expectation {} was called {} times, but expected at least {}",
+                state.path,
+                i + 1,
+                e.calls,
+                e.min
+            )
+        })
+    })
+}
+
+impl<Args, Ret> Drop for Mock<Args, Ret> {
+    fn drop(&mut self) {
+        // a second panic while unwinding would abort, and the first is more useful
+        if std::thread::panicking() {
+            return;
+        }
+        if let Some(message) = unmet(&lock(&self.state)) {
+            panic!("{}", message);
+        }
+    }
+}
+
+/// An expectation being set up. Made by [`expect_call`](Mock::expect_call).
+pub struct Expect<'a, Args, Ret> {
+    state: MutexGuard<'a, State<Args, Ret>>,
+}
+
+impl<Args, Ret> Expect<'_, Args, Ret> {
+    fn get(&mut self) -> &mut Expectation<Args, Ret> {
+        self.state.expectations.last_mut().unwrap()
+    }
+    /// Only match calls whose arguments pass `matcher`: one [`Predicate`](Predicate) for
+    /// a function of one argument, and a tuple of them for more.
+    pub fn with<M: Matcher<Args> + Send + 'static>(&mut self, matcher: M) -> &mut Self {
+        self.get().matcher = Box::new(move |args| matcher.matches(args));
+        self
+    }
+    /// Only match calls whose arguments, as a tuple, pass `f`.
+    pub fn withf<F: Fn(&Args) -> bool + Send + 'static>(&mut self, f: F) -> &mut Self {
+        self.get().matcher = Box::new(f);
+        self
+    }
+    /// Expect exactly `n` calls.
+    pub fn times(&mut self, n: usize) -> &mut Self {
+        let e = self.get();
+        e.min = n;
+        e.max = n;
+        self
+    }
+    /// Expect at least `n` calls, with no upper limit.
+    pub fn at_least(&mut self, n: usize) -> &mut Self {
+        let e = self.get();
+        e.min = n;
+        e.max = usize::MAX;
+        self
+    }
+    /// Allow up to `n` calls, including none.
+    pub fn at_most(&mut self, n: usize) -> &mut Self {
+        let e = self.get();
+        e.min = 0;
+        e.max = n;
+        self
+    }
+    /// Expect no calls at all. Matching calls panic rather than fall through to a later
+    /// expectation.
+    pub fn never(&mut self) -> &mut Self {
+        self.times(0)
+    }
+    /// Answer every call with a clone of `ret`.
+    pub fn return_const(&mut self, ret: Ret) -> &mut Self
+    where
+        Ret: Clone + Send + 'static,
+    {
+        self.get().returning = Some(Box::new(move |_| ret.clone()));
+        self
+    }
+}
+
+#[cfg(doc)]
+impl<Args, Ret> Expect<'_, Args, Ret> {
+    /// Answer calls with `f`, which takes the same arguments as the function.
+    pub fn returning<F, VaGen>(&mut self, f: F) -> &mut Self
+    where
+        F: FnMut(VaGen) -> Ret + Send + 'static,
+    {
+        // The actual implementation is below
+    }
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        impl<Ret, $($va_idents,)*> Expect<'_, ($($va_idents,)*), Ret>
+        {
+            #[allow(unused_variables)]
+            pub fn returning<F>(&mut self, mut f: F) -> &mut Self
+            where
+                F: FnMut($($va_idents,)*) -> Ret + Send + 'static,
+            {
+            self.get().returning = Some(Box::new(move |args: ($($va_idents,)*)| f($(args.$va_indices,)*)));
+            self
+            }
+        }
+}
+
+/// Public interface for [`mock`](HotpatchMock::mock); requires import to use.
+pub trait HotpatchMock<Args, Ret> {
+    /// Override the function on this thread with a [`Mock`](Mock) that has no expectations
+    /// yet. See the [module documentation](self).
+    ///
+    /// Only implemented for functions whose arguments and return type are `'static`:
+    /// ```compile_fail
+    /// # use hotpatch::*;
+    /// # use hotpatch::mock::*;
+    /// #[patchable]
+    /// fn len(s: &str) -> usize { s.len() }
+    ///
+    /// # fn main() {
+    /// let _m = len.mock(); // len borrows its argument
+    /// # }
+    /// ```
+    fn mock(&self) -> Mock<Args, Ret>;
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        // for the exact type, so a function taking references doesn't match with them as 'static
+        impl<Ret: 'static, $($va_idents: 'static,)*> HotpatchMock<($($va_idents,)*), Ret>
+        for Patchable<dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static>
+        {
+            fn mock(&self) -> Mock<($($va_idents,)*), Ret> {
+            let state = Arc::new(Mutex::new(State {
+                path: self.read().path,
+                expectations: vec![],
+            }));
+            let caller: Box<dyn Fn($($va_idents,)*) -> Ret + Send + Sync> = Box::new(Caller {
+                state: state.clone(),
+            });
+            let def = Definition {
+                ptr: unsafe { std::mem::transmute(caller) },
+                lib: None,
+                state: PatchState::Closure,
+                wrap: None,
+                #[cfg(feature = "stats")]
+                stats: Default::default(),
+            };
+            Mock {
+                state,
+                _patch: LocalPatch::new(self.key(), def.into()),
+            }
+            }
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[patchable]
+    fn add(a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    #[test]
+    fn earliest_matching_expectation_answers() {
+        let mut m = add.mock();
+        m.expect_call().with((eq(1), always())).times(2).returning(|_, _| 10);
+        m.expect_call().withf(|&(a, b)| a == b).returning(|a, _| a);
+        m.expect_call().at_least(0).return_const(-1);
+        assert_eq!(add(1, 1), 10);
+        assert_eq!(add(2, 2), 2);
+        // the first expectation is used up, so this falls through to the last
+        assert_eq!(add(1, 5), 10);
+        assert_eq!(add(1, 1), -1);
+        assert_eq!(add(3, 4), -1);
+    }
+
+    #[patchable]
+    fn compared(a: i32) -> i32 {
+        a
+    }
+
+    #[test]
+    fn comparison_predicates() {
+        let mut m = compared.mock();
+        m.expect_call().with(lt(0)).at_least(1).return_const(-1);
+        m.expect_call().with(ge(10)).at_least(1).return_const(1);
+        m.expect_call().with(ne(5)).at_least(1).return_const(0);
+        assert_eq!(compared(-3), -1);
+        assert_eq!(compared(10), 1);
+        assert_eq!(compared(4), 0);
+        m.checkpoint();
+    }
+
+    #[patchable]
+    fn unmet_calls() -> i32 {
+        0
+    }
+
+    #[test]
+    #[should_panic(expected = "expectation 1 was called 1 times, but expected at least 2")]
+    fn unmet_expectation_panics_on_drop() {
+        let mut m = unmet_calls.mock();
+        m.expect_call().times(2).return_const(1);
+        unmet_calls();
+    }
+
+    #[patchable]
+    fn unmatched(a: i32) -> i32 {
+        a
+    }
+
+    #[test]
+    #[should_panic(expected = "called with arguments no expectation matches")]
+    fn unmatched_call_panics() {
+        let mut m = unmatched.mock();
+        m.expect_call().with(eq(1)).at_most(1).return_const(1);
+        unmatched(2);
+    }
+
+    struct Named {
+        name: String,
+    }
+
+    #[patchable]
+    impl Named {
+        fn new(name: &'static str) -> Self {
+            Self { name: name.to_owned() }
+        }
+        fn into_name(self) -> String {
+            self.name
+        }
+    }
+
+    #[test]
+    fn associated_fns_and_methods() {
+        let mut new = Named::new.mock();
+        new.expect_call().with(eq("a")).returning(|_| Named { name: "mocked".to_owned() });
+        let mut into_name = Named::into_name_patchable.mock();
+        into_name.expect_call().withf(|(n,)| n.name == "mocked").returning(|n| n.name + "!");
+        assert_eq!(Named::new("a").into_name(), "mocked!");
+    }
+
+    #[patchable]
+    fn forbidden(a: i32) -> i32 {
+        a
+    }
+
+    #[test]
+    #[should_panic(expected = "called more times than expected")]
+    fn never_panics_when_called() {
+        let mut m = forbidden.mock();
+        m.expect_call().with(lt(0)).never();
+        m.expect_call().at_least(0).return_const(0);
+        forbidden(-1);
+    }
+}
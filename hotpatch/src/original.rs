@@ -0,0 +1,72 @@
+use std::mem::transmute_copy;
+
+use once_cell::sync::OnceCell;
+
+use crate::FnVoid;
+
+/// Calls the default definition of the [`Patchable`](crate::Patchable) a
+/// [`#[patch]`](crate::patch) is loaded into, ie the function as it was compiled in the
+/// host binary. Only usable in the body of a `#[patch]`.
+///
+/// `original!()` is a `&dyn Fn` with the patch's own signature, so a patch can handle a
+/// special case and leave the rest to the code it replaces. It's resolved when the
+/// library is loaded, and panics if the patch is called any other way, such as
+/// directly from the library.
+///
+/// ## Example
+/// ```
+/// # use hotpatch::*;
+/// # fn main() {}
+/// #[patch]
+/// fn parse_port(s: &str) -> u16 {
+///     if s.is_empty() {
+///         return 8080;
+///     }
+///     original!()(s)
+/// }
+/// ```
+#[macro_export]
+macro_rules! original {
+    () => {
+        compile_error!("original!() can only be used in the body of a #[patch]")
+    };
+}
+
+/// Created by [`#[patch]`](crate::patch) for a body that calls [`original!()`](crate::original).
+/// Internal use only.
+///
+/// Holds the default definition of the host's [`Patchable`](crate::Patchable), which is
+/// set when the patch is loaded.
+pub struct Original<T: ?Sized + 'static> {
+    ptr: OnceCell<&'static T>,
+}
+
+#[doc(hidden)]
+impl<T: ?Sized + 'static> Original<T> {
+    pub const fn __new() -> Self {
+        Self {
+            ptr: OnceCell::new(),
+        }
+    }
+    pub fn __get(&self) -> &'static T {
+        match self.ptr.get() {
+            Some(ptr) => ptr,
+            None => panic!("original!() was called before this patch was loaded into a Patchable"),
+        }
+    }
+}
+
+/// An [`Original`](Original) with its function type erased. Internal use only.
+#[doc(hidden)]
+pub trait OriginalSlot: Sync {
+    /// # Safety
+    /// `ptr` must be a definition of the type the slot was created with.
+    unsafe fn __set(&self, ptr: &'static FnVoid);
+}
+
+impl<T: ?Sized + Sync + 'static> OriginalSlot for Original<T> {
+    unsafe fn __set(&self, ptr: &'static FnVoid) {
+        // a library loaded twice shares its statics, and is given the same definition
+        let _ = self.ptr.set(transmute_copy(&ptr));
+    }
+}
@@ -0,0 +1,157 @@
+use std::any::Any;
+use std::sync::{PoisonError, RwLock, TryLockError};
+
+use crate::{Definition, HotpatchImportInternal, Lazy, Lock, PatchState};
+
+/// What a [`Patchable`](crate::Patchable) does when one of its patched definitions panics.
+/// Set by [`#[patchable(catch_panic)]`](crate::patchable). Internal use only.
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnPanic {
+    /// Let the panic through untouched.
+    Propagate,
+    /// Report the panic to the hook.
+    Report,
+    /// Also restore the default definition.
+    Revert,
+}
+
+/// A panic caught in a patched definition of a
+/// [`#[patchable(catch_panic)]`](crate::patchable) function.
+///
+/// Passed to the hook set with [`set_panic_hook`](set_panic_hook).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PatchPanic {
+    /// Full module path of the function, as in the [`Registry`](crate::Registry).
+    pub path: &'static str,
+    /// The definition that panicked.
+    pub state: PatchState,
+    /// The panic message, if it had one.
+    pub message: String,
+    /// Whether the default definition was put back. See
+    /// [`#[patchable(catch_panic)]`](crate::patchable).
+    pub reverted: bool,
+}
+
+type Hook = dyn Fn(&PatchPanic) + Send + Sync;
+
+static HOOK: Lazy<RwLock<Option<Box<Hook>>>> = Lazy::new(Default::default);
+
+/// Set the function called whenever a [`#[patchable(catch_panic)]`](crate::patchable)
+/// function catches a panic, replacing any previous hook.
+///
+/// The hook runs on the thread that panicked, after the default definition has been
+/// put back, if it was going to be, and before the panic continues to the caller.
+///
+/// ## Example
+/// ```
+/// hotpatch::set_panic_hook(|p| {
+///     eprintln!("{} ({}) panicked: {}", p.path, p.state, p.message);
+/// });
+/// ```
+pub fn set_panic_hook<F: Fn(&PatchPanic) + Send + Sync + 'static>(hook: F) {
+    *HOOK.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+}
+
+// Report a panic caught from def, revert if asked to, and carry on unwinding.
+// Panics are also caught for health checks, which aren't reported.
+pub(crate) fn panicked<RealType: ?Sized + Send + Sync + 'static>(
+    lock: &Lock<HotpatchImportInternal<RealType>>,
+    path: &'static str,
+    on_panic: OnPanic,
+    def: &Definition,
+    payload: Box<dyn Any + Send>,
+) -> ! {
+    // The payload may have been made by the library, and is passed on to the caller,
+    // who can keep it as long as they like. The library can't be closed before that,
+    // by reverting or otherwise, so it's never closed.
+    let message = message(&*payload);
+    pin(def);
+
+    // Waiting for the lock could deadlock if this call is nested in another to the same
+    // function, so if it's held the revert is skipped. A later panic will try again.
+    let reverted = on_panic == OnPanic::Revert
+        && match lock.try_write() {
+            Ok(mut inner) => inner.is_current(def) && inner.restore_default().is_ok(),
+            Err(TryLockError::Poisoned(e)) => {
+                let mut inner = e.into_inner();
+                inner.is_current(def) && inner.restore_default().is_ok()
+            }
+            Err(TryLockError::WouldBlock) => {
+                event!(DEBUG, patchable = path, "lock held, revert after panic skipped");
+                false
+            }
+        };
+
+    let report = PatchPanic {
+        path,
+        state: def.state.clone(),
+        message,
+        reverted,
+    };
+    if on_panic != OnPanic::Propagate {
+        if let Some(hook) = &*HOOK.read().unwrap_or_else(PoisonError::into_inner) {
+            hook(&report);
+        }
+    }
+    std::panic::resume_unwind(payload)
+}
+
+// Keep every library def runs, including the ones it wraps, loaded until the process exits.
+// Definitions without a library are left alone, as the payload can't have come from one.
+fn pin(def: &Definition) {
+    if let Some(lib) = &def.lib {
+        lib.pin();
+    }
+    if let Some(wrap) = &def.wrap {
+        pin(&wrap.prev);
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::*;
+
+    #[patchable(catch_panic)]
+    fn fragile() {}
+
+    #[test]
+    fn libraries_are_pinned_once() {
+        let lib = Arc::new(LoadedLibrary::unopened(PathBuf::from("libfragile.so"), false));
+        let exploding: Box<FnVoid> = Box::new(|| panic!("from the library"));
+        fragile.write().push(
+            Definition {
+                ptr: exploding,
+                lib: Some(lib.clone()),
+                state: PatchState::Library("libfragile.so".to_owned()),
+                wrap: None,
+                #[cfg(feature = "stats")]
+                stats: Default::default(),
+            }
+            .into(),
+        );
+        for _ in 0..3 {
+            // a closure doesn't capture the static, so it's unwind safe with any lock
+            #[allow(clippy::redundant_closure)]
+            let panicked = std::panic::catch_unwind(|| fragile()).is_err();
+            assert!(panicked);
+        }
+        // this one, the definition's, and the one kept for the panics
+        assert_eq!(Arc::strong_count(&lib), 3);
+        fragile.restore_default().unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use crate::target::{same_target, swap_all};
+use crate::{
+    load_library, retire, HotpatchError, HotpatchImportInternal, HotpatchLibInternal,
+    LoadedLibrary, Patchable, SharedDefinition, Target,
+};
+
+/// Hotpatches several [`Patchable`](crate::Patchable)s from one library at once.
+///
+/// Patching each function on its own lets callers see a new `foo` next to an
+/// old `bar` in between the two patches. A `PatchSet` loads the library once
+/// and checks every export up front, then [`commit`](PatchSet::commit) swaps
+/// all of the definitions while holding every lock, so calls see either all of
+/// the old definitions or all of the new ones.
+///
+/// If any export is missing or has the wrong signature, [`add`](PatchSet::add)
+/// returns the error and nothing has been patched yet. Dropping the set then
+/// leaves every function as it was.
+///
+/// ## Example
+/// ```no_run
+/// # use hotpatch::*;
+/// #[patchable]
+/// fn foo() {}
+/// #[patchable]
+/// fn bar() {}
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///   let mut set = PatchSet::new("libtest.so")?;
+///   set.add(&foo)?.add(&bar)?;
+///   let patches = set.commit()?; // foo and bar are patched together
+///   patches.rollback()?; // and restored together
+///   Ok(())
+/// }
+/// ```
+///
+/// ## Locking
+/// Like [`hotpatch_lib`](crate::Patchable::hotpatch_lib), committing waits for
+/// calls in progress to finish, so don't commit from inside a function that is
+/// part of the set: that fails with [`HotpatchError::WouldBlock`](HotpatchError::WouldBlock)
+/// once it's waited a second. Locks are only ever taken all at once, and given back
+/// while waiting, so calls between functions in the set can't deadlock a commit.
+///
+/// With the `lockfree` feature calls never take a lock. Commits are still
+/// all-or-nothing and serialized with other patches, but a call made during a
+/// commit can see some functions already swapped and others not yet.
+pub struct PatchSet<'a> {
+    lib: Arc<LoadedLibrary>,
+    lib_name: String,
+    targets: Vec<&'a dyn Target>,
+    defs: Vec<SharedDefinition>,
+}
+
+impl<'a> PatchSet<'a> {
+    /// Load a library to patch from.
+    pub fn new(lib_name: &str) -> Result<Self, HotpatchError> {
+        Ok(Self {
+            lib: Arc::new(load_library(lib_name)?),
+            lib_name: lib_name.to_owned(),
+            targets: vec![],
+            defs: vec![],
+        })
+    }
+    /// Stage the library's export for `target`, checking that it exists and that its
+    /// signature matches. Nothing is patched until [`commit`](PatchSet::commit).
+    ///
+    /// Adding the same function twice only patches it once.
+    pub fn add<RealType, Dummy>(
+        &mut self,
+        target: &'a Patchable<RealType>,
+    ) -> Result<&mut Self, HotpatchError>
+    where
+        RealType: ?Sized + Send + Sync + 'static,
+        HotpatchImportInternal<RealType>: HotpatchLibInternal<Dummy>,
+    {
+        let def = target.read().definition(&self.lib, &self.lib_name)?.into();
+        let target: &'a dyn Target = target;
+        match self.targets.iter().position(|t| same_target(*t, target)) {
+            Some(i) => self.defs[i] = def,
+            None => {
+                self.targets.push(target);
+                self.defs.push(def);
+            }
+        }
+        Ok(self)
+    }
+    /// Patch every staged function at once.
+    ///
+    /// The definitions that were replaced are kept, along with their libraries,
+    /// so that the whole set can be undone with
+    /// [`CommittedPatchSet::rollback`](CommittedPatchSet::rollback).
+    ///
+    /// Waits for calls to the functions to return, but gives up with
+    /// [`HotpatchError::WouldBlock`](HotpatchError::WouldBlock) if they can't all be
+    /// locked within a second, for example when committing from inside one of them.
+    /// Nothing is patched then.
+    pub fn commit(self) -> Result<CommittedPatchSet<'a>, HotpatchError> {
+        let previous = swap_all(&self.targets, self.defs, |l, d| l.push(d))?;
+        Ok(CommittedPatchSet {
+            targets: self.targets,
+            previous,
+        })
+    }
+}
+
+/// A [`PatchSet`](PatchSet) after it has been committed.
+///
+/// Dropping this keeps the patches in place.
+pub struct CommittedPatchSet<'a> {
+    targets: Vec<&'a dyn Target>,
+    previous: Vec<SharedDefinition>,
+}
+
+impl<'a> CommittedPatchSet<'a> {
+    /// Put back the definitions every function had before the commit, again all at once.
+    ///
+    /// They're taken back out of each function's history, as if
+    /// [`Patchable::rollback`](crate::Patchable::rollback) had been called on each.
+    ///
+    /// Like [`PatchSet::commit`](PatchSet::commit), this fails with
+    /// [`HotpatchError::WouldBlock`](HotpatchError::WouldBlock) if the functions can't
+    /// all be locked within a second, and then nothing is put back.
+    pub fn rollback(self) -> Result<(), HotpatchError> {
+        swap_all(&self.targets, self.previous, |l, d| l.restore(d))?
+            .into_iter()
+            .try_for_each(retire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::transmute;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::*;
+
+    #[patchable]
+    fn left() -> i32 {
+        1
+    }
+
+    #[patchable]
+    fn right() -> i32 {
+        2
+    }
+
+    type Def = dyn Fn() -> i32 + Send + Sync + 'static;
+
+    // as the export returning n would be loaded from lib
+    fn exported(lib: &Arc<LoadedLibrary>, n: i32) -> SharedDefinition {
+        Definition {
+            ptr: unsafe { transmute::<Box<Def>, Box<FnVoid>>(Box::new(move || n)) },
+            lib: Some(lib.clone()),
+            state: PatchState::Library("libset.so".to_owned()),
+            wrap: None,
+            #[cfg(feature = "stats")]
+            stats: Default::default(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn committed_sets_roll_back_together() {
+        let lib = Arc::new(LoadedLibrary::unopened(PathBuf::from("libset.so"), false));
+        let set = PatchSet {
+            lib: lib.clone(),
+            lib_name: "libset.so".to_owned(),
+            targets: vec![&left, &right],
+            defs: vec![exported(&lib, 10), exported(&lib, 20)],
+        };
+        let patches = set.commit().unwrap();
+        assert_eq!((left(), right()), (10, 20));
+        assert_eq!(left.state(), PatchState::Library("libset.so".to_owned()));
+        patches.rollback().unwrap();
+        assert_eq!((left(), right()), (1, 2));
+        assert!(matches!(left.rollback(), Err(HotpatchError::NoHistory)));
+        // nothing is left holding the library
+        assert_eq!(Arc::strong_count(&lib), 1);
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::target::swap_all;
+use crate::{
+    load_library, Definition, HotpatchError, HotpatchImportInternal, HotpatchLibInternal, Lazy,
+    LoadedLibrary, ManifestIndex, Patchable, SharedDefinition, Target,
+};
+
+/// Which definition a [`Patchable`](crate::Patchable) is currently running.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PatchState {
+    /// The original definition, as written in source.
+    Default,
+    /// A closure from [`hotpatch_fn`](crate::Patchable::hotpatch_fn).
+    Closure,
+    /// An export from the library at this path.
+    Library(String),
+    /// A wrapper from [`wrap`](crate::HotpatchWrap::wrap) or a
+    /// [`#[patch(wrap)]`](crate::patch) export, over another definition.
+    Wrapped {
+        by: Box<PatchState>,
+        over: Box<PatchState>,
+    },
+}
+
+impl fmt::Display for PatchState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchState::Default => write!(f, "default"),
+            PatchState::Closure => write!(f, "closure"),
+            PatchState::Library(lib) => write!(f, "library {}", lib),
+            PatchState::Wrapped { by, over } => write!(f, "{} wrapping {}", by, over),
+        }
+    }
+}
+
+/// Created by [`#[patchable]`](crate::patchable). One [`Patchable`](crate::Patchable)
+/// as listed in the [`Registry`](Registry), with its function type erased.
+pub struct RegistryEntry {
+    target: &'static dyn Target,
+    // HotpatchLibInternal::definition for the erased type of target
+    definition:
+        fn(&'static dyn Target, &Arc<LoadedLibrary>, &str) -> Result<Definition, HotpatchError>,
+}
+
+#[doc(hidden)]
+impl RegistryEntry {
+    pub const fn __new<RealType, Dummy>(patchable: &'static Patchable<RealType>) -> Self
+    where
+        RealType: ?Sized + Send + Sync + 'static,
+        HotpatchImportInternal<RealType>: HotpatchLibInternal<Dummy>,
+    {
+        Self {
+            target: patchable,
+            definition: definition::<RealType, Dummy>,
+        }
+    }
+}
+
+fn definition<RealType, Dummy>(
+    target: &'static dyn Target,
+    lib: &Arc<LoadedLibrary>,
+    lib_name: &str,
+) -> Result<Definition, HotpatchError>
+where
+    RealType: ?Sized + Send + Sync + 'static,
+    HotpatchImportInternal<RealType>: HotpatchLibInternal<Dummy>,
+{
+    // only ever called with the target it was created alongside, in RegistryEntry::__new
+    let patchable = unsafe { &*(target as *const dyn Target as *const Patchable<RealType>) };
+    patchable.read().definition(lib, lib_name)
+}
+
+impl RegistryEntry {
+    /// Full module path, including the crate name, such as `my_bin::foo`. Items
+    /// given a path with [`#[patchable(mymod::baz)]`](crate::patchable) don't have
+    /// one, and are listed as `::mymod::baz`. For methods this is the key exports
+    /// are matched by, such as `my_bin::!__associated_fn:Foo:new`.
+    pub fn path(&self) -> &'static str {
+        self.target.path()
+    }
+    /// Signature that definitions must match, such as `fn(i32) -> ()`.
+    pub fn sig(&self) -> &'static str {
+        self.target.sig()
+    }
+    /// Which definition is currently in place.
+    pub fn state(&self) -> PatchState {
+        self.target.state()
+    }
+    /// Calls made to each definition still around: the current one first, then the
+    /// previous ones kept for [`rollback`](crate::Patchable::rollback) from most recent,
+    /// and the default definition. Requires the `stats` feature.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// let foo = hotpatch::registry().get("my_bin::foo").unwrap();
+    /// for stats in foo.stats() {
+    ///     println!("{}: {} calls, {} panicked, p99 {:?}",
+    ///              stats.state, stats.calls, stats.panics, stats.latency.quantile(0.99));
+    /// }
+    /// ```
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Vec<crate::CallStats> {
+        self.target.stats()
+    }
+}
+
+impl fmt::Debug for RegistryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RegistryEntry")
+            .field("path", &self.path())
+            .field("sig", &self.sig())
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+/// Every [`#[patchable]`](crate::patchable) in the binary, collected at link time.
+/// Internal use only.
+#[doc(hidden)]
+#[linkme::distributed_slice]
+pub static HOTPATCH_REGISTRY: [RegistryEntry] = [..];
+
+/// Every [`#[patchable]`](crate::patchable) item in this binary, by module path.
+///
+/// Paths given with [`#[patchable(mymod::baz)]`](crate::patchable) aren't unique, so
+/// more than one item can share a path. They're all listed.
+///
+/// Returned by [`registry`](registry).
+pub struct Registry {
+    // sorted by path
+    entries: Vec<&'static RegistryEntry>,
+}
+
+impl Registry {
+    /// Iterate over every item, sorted by path.
+    pub fn iter(&self) -> impl Iterator<Item = &'static RegistryEntry> + '_ {
+        self.entries.iter().copied()
+    }
+    /// Look up an item by its full module path, as given by [`RegistryEntry::path`].
+    /// If more than one item has that path, this is the first, see [`get_all`](Registry::get_all).
+    pub fn get(&self, path: &str) -> Option<&'static RegistryEntry> {
+        self.get_all(path).next()
+    }
+    /// Every item with this full module path.
+    pub fn get_all<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> impl Iterator<Item = &'static RegistryEntry> + 'a {
+        let start = self.entries.partition_point(|e| e.path() < path);
+        self.entries[start..]
+            .iter()
+            .copied()
+            .take_while(move |e| e.path() == path)
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+static REGISTRY: Lazy<Registry> = Lazy::new(|| {
+    let mut entries: Vec<_> = HOTPATCH_REGISTRY.iter().collect();
+    entries.sort_by_key(|e| e.path());
+    Registry { entries }
+});
+
+/// Get the process-wide [`Registry`](Registry) of [`#[patchable]`](crate::patchable) items.
+///
+/// ## Example
+/// ```
+/// for item in hotpatch::registry().iter() {
+///     println!("{} {}: {}", item.path(), item.sig(), item.state());
+/// }
+/// ```
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+/// What [`apply_library`](apply_library) did with each export in the library.
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    /// Paths of the [`Patchable`](crate::Patchable)s that were hotpatched.
+    pub applied: Vec<&'static str>,
+    /// Exports with no matching [`Patchable`](crate::Patchable) in this binary,
+    /// by module path without the crate name.
+    pub skipped: Vec<String>,
+    /// Exports that matched a [`Patchable`](crate::Patchable) by path but not by
+    /// signature. Each is a [`HotpatchError::SignatureMismatch`](HotpatchError::SignatureMismatch)
+    /// or [`HotpatchError::LayoutMismatch`](HotpatchError::LayoutMismatch), and the
+    /// [`Patchable`](crate::Patchable) was left as it was.
+    pub mismatched: Vec<HotpatchError>,
+}
+
+/// Hotpatch every [`#[patchable]`](crate::patchable) item in the [`Registry`](Registry)
+/// that has an export in a library.
+///
+/// The library is only loaded once, and every definition shares it. Exports are matched by
+/// module path and signatures are checked like in
+/// [`hotpatch_lib`](crate::Patchable::hotpatch_lib), but a mismatch only skips that one item.
+/// Module paths don't include the crate name, so an export patches every item with its path,
+/// whichever crate it's in.
+/// Everything that does match is swapped in at once, like a [`PatchSet`](crate::PatchSet),
+/// and fails with [`HotpatchError::WouldBlock`](HotpatchError::WouldBlock) in the same way
+/// as [`PatchSet::commit`](crate::PatchSet::commit).
+///
+/// ## Example
+/// ```no_run
+/// # use hotpatch::HotpatchError;
+/// fn main() -> Result<(), HotpatchError> {
+///     let report = hotpatch::apply_library("libsomething.so")?;
+///     println!("patched {:?}", report.applied);
+///     for e in report.mismatched {
+///         eprintln!("{}", e);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn apply_library(lib_name: &str) -> Result<ApplyReport, HotpatchError> {
+    apply(Arc::new(load_library(lib_name)?), lib_name)
+}
+
+pub(crate) fn apply(lib: Arc<LoadedLibrary>, lib_name: &str) -> Result<ApplyReport, HotpatchError> {
+    let exports = unsafe { ManifestIndex::read(&lib, lib_name)? }.names();
+    let (report, targets, defs) = match_exports(exports, |entry| {
+        (entry.definition)(entry.target, &lib, lib_name)
+    })?;
+    swap_all(&targets, defs, |l, d| l.push(d))?;
+    Ok(report)
+}
+
+// Find the items in the registry each export is for, and make their definitions.
+// Returns what to swap in, with the report of what was found.
+#[allow(clippy::type_complexity)]
+fn match_exports(
+    exports: Vec<&str>,
+    mut definition: impl FnMut(&RegistryEntry) -> Result<Definition, HotpatchError>,
+) -> Result<(ApplyReport, Vec<&'static dyn Target>, Vec<SharedDefinition>), HotpatchError> {
+    // registry paths include the crate name, but exports don't, so items with the
+    // same path in different crates all match
+    let mut by_mpath: HashMap<&str, Vec<&'static RegistryEntry>> = HashMap::new();
+    for e in registry().iter() {
+        by_mpath
+            .entry(e.path().trim_start_matches(|c| c != ':'))
+            .or_default()
+            .push(e);
+    }
+
+    let mut report = ApplyReport::default();
+    let mut targets = vec![];
+    let mut defs = vec![];
+    for mpath in exports {
+        let entries = match by_mpath.get(mpath) {
+            Some(entries) => entries,
+            None => {
+                report.skipped.push(mpath.to_owned());
+                continue;
+            }
+        };
+        for entry in entries {
+            match definition(entry) {
+                Ok(def) => {
+                    targets.push(entry.target);
+                    defs.push(def.into());
+                    report.applied.push(entry.path());
+                }
+                Err(e @ HotpatchError::SignatureMismatch { .. })
+                | Err(e @ HotpatchError::LayoutMismatch { .. }) => report.mismatched.push(e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok((report, targets, defs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::transmute;
+
+    use super::*;
+    use crate::*;
+
+    #[patchable(registry_tests::shared)]
+    fn shared_a() -> i32 {
+        1
+    }
+
+    #[patchable(registry_tests::shared)]
+    fn shared_b() -> i32 {
+        2
+    }
+
+    #[patchable]
+    fn listed() {}
+
+    #[test]
+    fn items_sharing_a_path_are_all_listed() {
+        let shared: Vec<_> = registry().get_all("::registry_tests::shared").collect();
+        assert_eq!(shared.len(), 2);
+        assert!(shared.iter().all(|e| e.sig() == "fn() -> i32"));
+        assert!(registry().get("::registry_tests::shared").is_some());
+        assert_eq!(
+            registry()
+                .iter()
+                .filter(|e| e.path() == "::registry_tests::shared")
+                .count(),
+            2
+        );
+        assert_eq!(shared_a() + shared_b(), 3);
+    }
+
+    #[test]
+    fn paths_include_the_crate_name() {
+        let entry = registry().get("hotpatch::registry::tests::listed").unwrap();
+        assert_eq!(entry.sig(), "fn() -> ()");
+        assert_eq!(entry.state(), PatchState::Default);
+        assert!(registry().get("::registry::tests::listed").is_none());
+        assert_eq!(registry().get_all("hotpatch::registry::tests").count(), 0);
+        listed();
+    }
+
+    #[patchable(registry_tests::matched)]
+    fn matched_i32() -> i32 {
+        1
+    }
+
+    #[patchable(registry_tests::matched)]
+    fn matched_u32() -> u32 {
+        1
+    }
+
+    type Exported = dyn Fn() -> i32 + Send + Sync + 'static;
+
+    // as an export for matched_i32 would be loaded
+    fn exported() -> Definition {
+        Definition {
+            ptr: unsafe { transmute::<Box<Exported>, Box<FnVoid>>(Box::new(|| 2)) },
+            lib: None,
+            state: PatchState::Library("libexported.so".to_owned()),
+            wrap: None,
+            #[cfg(feature = "stats")]
+            stats: Default::default(),
+        }
+    }
+
+    #[test]
+    fn apply_report_lists_every_export() {
+        let exports = vec!["::nowhere", "::registry_tests::matched"];
+        let (report, targets, defs) = match_exports(exports, |entry| match entry.sig() {
+            "fn() -> i32" => Ok(exported()),
+            sig => Err(HotpatchError::SignatureMismatch {
+                symbol: entry.path().to_owned(),
+                expected: sig.to_owned(),
+                found: "fn() -> i32".to_owned(),
+            }),
+        })
+        .unwrap();
+        assert_eq!(report.applied, vec!["::registry_tests::matched"]);
+        assert_eq!(report.skipped, vec!["::nowhere".to_owned()]);
+        assert!(matches!(
+            &report.mismatched[..],
+            [HotpatchError::SignatureMismatch { expected, .. }] if expected == "fn() -> u32"
+        ));
+        // only what matched is swapped in
+        swap_all(&targets, defs, |l, d| l.push(d)).unwrap();
+        assert_eq!(matched_i32(), 2);
+        assert_eq!(matched_u32(), 1);
+        let states: Vec<_> = registry()
+            .get_all("::registry_tests::matched")
+            .map(|e| e.state())
+            .collect();
+        assert!(states.contains(&PatchState::Library("libexported.so".to_owned())));
+        assert!(states.contains(&PatchState::Default));
+    }
+
+    #[test]
+    fn other_errors_fail_the_whole_library() {
+        let exports = vec!["::registry_tests::matched"];
+        let result = match_exports(exports, |_| Err(HotpatchError::NoHistory));
+        assert!(matches!(result, Err(HotpatchError::NoHistory)));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{retire, SharedDefinition, Target};
+
+/// Returned by [`scoped_patch`](crate::HotpatchScoped::scoped_patch). Puts back the
+/// previous definition when dropped.
+///
+/// Dropping waits for the lock like [`hotpatch_fn`](crate::HotpatchFn::hotpatch_fn),
+/// so don't drop a guard from inside the function it patched.
+#[must_use = "the patch is undone as soon as the guard is dropped"]
+pub struct ScopedPatch<'a> {
+    target: &'a dyn Target,
+    installed: Option<SharedDefinition>,
+    previous: Option<SharedDefinition>,
+}
+
+impl<'a> ScopedPatch<'a> {
+    pub(crate) fn new(
+        target: &'a dyn Target,
+        installed: SharedDefinition,
+        previous: SharedDefinition,
+    ) -> Self {
+        Self {
+            target,
+            installed: Some(installed),
+            previous: Some(previous),
+        }
+    }
+}
+
+impl Drop for ScopedPatch<'_> {
+    fn drop(&mut self) {
+        let (installed, previous) = (self.installed.take(), self.previous.take());
+        let old = self
+            .target
+            .lock()
+            .unscope(installed.unwrap(), previous.unwrap());
+        // a library that fails to close can't be reported from here
+        if let Some(old) = old {
+            let _ = retire(old);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[patchable]
+    fn nested() -> i32 {
+        0
+    }
+
+    #[test]
+    fn nested_guards_restore_in_order() {
+        {
+            let _outer = nested.scoped_patch(|| 1).unwrap();
+            assert_eq!(nested(), 1);
+            {
+                let _inner = nested.scoped_patch(|| 2).unwrap();
+                assert_eq!(nested(), 2);
+            }
+            assert_eq!(nested(), 1);
+        }
+        assert_eq!(nested(), 0);
+    }
+
+    #[patchable]
+    fn out_of_order() -> i32 {
+        0
+    }
+
+    #[test]
+    fn outer_guard_dropped_first() {
+        let outer = out_of_order.scoped_patch(|| 1).unwrap();
+        let inner = out_of_order.scoped_patch(|| 2).unwrap();
+        drop(outer);
+        // the later patch is still in place
+        assert_eq!(out_of_order(), 2);
+        drop(inner);
+        // and the outer one was taken out from underneath it
+        assert_eq!(out_of_order(), 0);
+    }
+
+    #[patchable]
+    fn over_patch() -> i32 {
+        0
+    }
+
+    #[test]
+    fn guard_restores_existing_patch() {
+        over_patch.hotpatch_fn(|| 1).unwrap();
+        {
+            let _g = over_patch.scoped_patch(|| 2).unwrap();
+            assert_eq!(over_patch(), 2);
+        }
+        assert_eq!(over_patch(), 1);
+        over_patch.restore_default().unwrap();
+    }
+}
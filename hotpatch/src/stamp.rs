@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::RwLock;
+
+use crate::{HotpatchError, Lazy};
+
+/// Which parts of a patch library's build have to match the binary loading it.
+///
+/// Rust has no stable ABI, so a library built by a different compiler, for a different
+/// target, or with a different version or configuration of `hotpatch` can't be safely
+/// called. Every library carries a stamp of how it was built, and loading one that differs
+/// in a checked part fails with
+/// [`HotpatchError::BuildMismatch`](crate::HotpatchError::BuildMismatch).
+///
+/// Everything but `features` is checked by default. Set with
+/// [`set_build_check`](set_build_check).
+///
+/// The stamp is read from the library once it's open, so a rejected library's
+/// initialisers have still run. See [Build Compatibility](crate#build-compatibility).
+///
+/// ## Example
+/// ```
+/// // a release build loading patches from a debug build
+/// hotpatch::set_build_check(hotpatch::BuildCheck {
+///     profile: false,
+///     ..Default::default()
+/// });
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildCheck {
+    /// The `rustc` version, including its commit hash.
+    pub rustc: bool,
+    /// The target triple.
+    pub target: bool,
+    /// The version of `hotpatch`.
+    pub hotpatch: bool,
+    /// The panic strategy, `unwind` or `abort`.
+    pub panic: bool,
+    /// Whether `hotpatch` was built with debug assertions.
+    pub profile: bool,
+    /// Which features of `hotpatch` were enabled. None of them change the exports or
+    /// manifest a library is read through, and one binary's `redirect-main` is enabled
+    /// for its libraries too when built in the same workspace, so this is off by default.
+    pub features: bool,
+}
+
+impl BuildCheck {
+    /// Check nothing. Libraries are still required to have a stamp.
+    pub const fn none() -> Self {
+        Self {
+            rustc: false,
+            target: false,
+            hotpatch: false,
+            panic: false,
+            profile: false,
+            features: false,
+        }
+    }
+    fn checks(&self, field: &str) -> bool {
+        match field {
+            "rustc" => self.rustc,
+            "target" => self.target,
+            "hotpatch" => self.hotpatch,
+            "panic" => self.panic,
+            "profile" => self.profile,
+            "features" => self.features,
+            _ => true,
+        }
+    }
+}
+
+impl Default for BuildCheck {
+    fn default() -> Self {
+        Self {
+            rustc: true,
+            target: true,
+            hotpatch: true,
+            panic: true,
+            profile: true,
+            features: false,
+        }
+    }
+}
+
+static CHECK: Lazy<RwLock<BuildCheck>> = Lazy::new(Default::default);
+
+/// Choose which parts of a library's build stamp are checked. Applies to every load after this call.
+pub fn set_build_check(check: BuildCheck) {
+    *CHECK.write().unwrap() = check;
+}
+
+const STAMP: &str = concat!(env!("HOTPATCH_BUILD_STAMP"), "\0");
+
+/// How this library was built, as `key=value` pairs separated by `;`. Internal use only.
+///
+/// Uses the C ABI, like the manifest, so that it can be read from a library
+/// built by any compiler.
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __HOTPATCH_BUILD_STAMP() -> *const c_char {
+    STAMP.as_ptr() as *const c_char
+}
+
+// Reject a library that was built differently, before anything else is read from it.
+// It's already open, so anything it runs on load has run.
+pub(crate) fn check(lib: &libloading::Library, lib_name: &str) -> Result<(), HotpatchError> {
+    let check = *CHECK.read().unwrap();
+    let stamp = unsafe {
+        let stamp: libloading::Symbol<extern "C" fn() -> *const c_char> = lib
+            .get(b"__HOTPATCH_BUILD_STAMP")
+            .map_err(|_| HotpatchError::NotAPatchLibrary {
+                lib: lib_name.to_owned(),
+            })?;
+        CStr::from_ptr(stamp()).to_string_lossy().into_owned()
+    };
+    compare(check, STAMP.trim_end_matches('\0'), &stamp, lib_name)
+}
+
+// The first checked field where the library's stamp differs from ours
+fn compare(
+    check: BuildCheck,
+    ours: &'static str,
+    theirs: &str,
+    lib_name: &str,
+) -> Result<(), HotpatchError> {
+    // looked up by key, as another version of hotpatch may stamp other things
+    let theirs: HashMap<&str, &str> = pairs(theirs).collect();
+    for (field, expected) in pairs(ours) {
+        let found = theirs.get(field).copied().unwrap_or("unknown");
+        if check.checks(field) && expected != found {
+            event!(WARN, field, expected, found, "build stamp mismatch");
+            return Err(HotpatchError::BuildMismatch {
+                lib: lib_name.to_owned(),
+                field,
+                expected: expected.to_owned(),
+                found: found.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn pairs(stamp: &str) -> impl Iterator<Item = (&str, &str)> {
+    stamp.split(';').filter_map(|kv| kv.split_once('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OURS: &str =
+        "rustc=rustc 1.0.0;target=x86_64;hotpatch=0.3.0;panic=unwind;profile=debug;features=";
+
+    #[test]
+    fn first_checked_difference_is_reported() {
+        let theirs =
+            "rustc=rustc 1.0.0;target=x86_64;hotpatch=0.3.0;panic=abort;profile=release;features=";
+        match compare(BuildCheck::default(), OURS, theirs, "libother.so") {
+            Err(HotpatchError::BuildMismatch {
+                lib,
+                field,
+                expected,
+                found,
+            }) => {
+                assert_eq!(lib, "libother.so");
+                assert_eq!(field, "panic");
+                assert_eq!(expected, "unwind");
+                assert_eq!(found, "abort");
+            }
+            other => panic!("{:?}", other),
+        }
+        let check = BuildCheck {
+            panic: false,
+            ..Default::default()
+        };
+        assert!(matches!(
+            compare(check, OURS, theirs, "libother.so"),
+            Err(HotpatchError::BuildMismatch {
+                field: "profile",
+                ..
+            })
+        ));
+        assert!(compare(BuildCheck::none(), OURS, theirs, "libother.so").is_ok());
+    }
+
+    #[test]
+    fn fields_are_matched_by_key() {
+        // reordered, with a field this version doesn't know about
+        let theirs = concat!(
+            "features=lockfree;newer=1;profile=debug;panic=unwind;",
+            "hotpatch=0.3.0;target=x86_64;rustc=rustc 1.0.0"
+        );
+        assert!(compare(BuildCheck::default(), OURS, theirs, "libnewer.so").is_ok());
+        let check = BuildCheck {
+            features: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            compare(check, OURS, theirs, "libnewer.so"),
+            Err(HotpatchError::BuildMismatch {
+                field: "features",
+                ..
+            })
+        ));
+        // and a missing one is unknown
+        match compare(
+            BuildCheck::default(),
+            OURS,
+            "rustc=rustc 1.0.0",
+            "libolder.so",
+        ) {
+            Err(HotpatchError::BuildMismatch { field, found, .. }) => {
+                assert_eq!((field, found.as_str()), ("target", "unknown"));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn this_build_matches_itself() {
+        let ours = STAMP.trim_end_matches('\0');
+        assert!(compare(BuildCheck::default(), ours, ours, "this").is_ok());
+        assert!(pairs(ours).any(|(field, _)| field == "rustc"));
+    }
+}
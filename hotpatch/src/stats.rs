@@ -0,0 +1,243 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::PatchState;
+
+// bucket i counts calls that took less than 2^i ns, and at least half that.
+// The last one also counts everything slower, from about 9 minutes.
+const BUCKETS: usize = 40;
+
+// What's been recorded for one definition, shared by every call to it
+pub(crate) struct Stats {
+    calls: AtomicU64,
+    panics: AtomicU64,
+    nanos: AtomicU64, // total, for the mean
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            calls: ZERO,
+            panics: ZERO,
+            nanos: ZERO,
+            buckets: [ZERO; BUCKETS],
+        }
+    }
+}
+
+impl Stats {
+    // time a call, which is recorded when the timer drops, even if by a panic
+    pub(crate) fn start(&self) -> Timer<'_> {
+        Timer {
+            stats: self,
+            start: Instant::now(),
+            returned: false,
+        }
+    }
+    fn record(&self, elapsed: Duration, panicked: bool) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = (64 - nanos.leading_zeros() as usize).min(BUCKETS - 1);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if panicked {
+            self.panics.fetch_add(1, Ordering::Relaxed);
+        }
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn snapshot(&self, state: PatchState, current: bool) -> CallStats {
+        CallStats {
+            state,
+            current,
+            calls: self.calls.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            latency: Latency {
+                total: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+                buckets: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect(),
+            },
+        }
+    }
+}
+
+pub(crate) struct Timer<'a> {
+    stats: &'a Stats,
+    start: Instant,
+    // set once the call returns, so a timer dropped without it was unwound by a panic
+    returned: bool,
+}
+
+impl Timer<'_> {
+    // record a call that returned
+    pub(crate) fn stop(mut self) {
+        self.returned = true;
+    }
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.stats.record(self.start.elapsed(), !self.returned);
+    }
+}
+
+/// Calls made to one definition of a [`Patchable`](crate::Patchable), as returned by
+/// [`RegistryEntry::stats`](crate::RegistryEntry::stats). Requires the `stats` feature.
+///
+/// Every closure and library gets its own, and the default definition keeps one across
+/// hotpatches, as does a wrapper when the wrappers below it change. Calls are counted for
+/// the definition the [`Patchable`](crate::Patchable) called, so a wrapper's calls through
+/// to the one below only count for the wrapper, and thread-local overrides aren't counted
+/// at all.
+#[derive(Clone, Debug)]
+pub struct CallStats {
+    /// Which definition these are for.
+    pub state: PatchState,
+    /// Whether it's the current definition.
+    pub current: bool,
+    /// Calls that have returned or panicked.
+    pub calls: u64,
+    /// Calls that panicked, which are included in `calls`.
+    pub panics: u64,
+    /// How long the calls took.
+    pub latency: Latency,
+}
+
+/// A histogram of call durations, with buckets in powers of two nanoseconds.
+#[derive(Clone, Debug)]
+pub struct Latency {
+    total: Duration,
+    buckets: Vec<u64>,
+}
+
+impl Latency {
+    /// Calls counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+    /// Average duration, if there have been any calls.
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            n => Some(Duration::from_nanos((self.total.as_nanos() / n as u128) as u64)),
+        }
+    }
+    /// Upper bound on the duration of the quickest `q` of calls, such as `0.99` for the
+    /// 99th percentile, if there have been any.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets().find_map(|(upper, n)| {
+            seen += n;
+            if seen >= rank {
+                Some(upper)
+            } else {
+                None
+            }
+        })
+    }
+    /// Every bucket from quickest to slowest, as the exclusive upper bound of its
+    /// durations and the number of calls in it. The last bucket has no upper bound,
+    /// and is given as [`Duration::MAX`](Duration::MAX).
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, &n)| {
+            let upper = match i {
+                i if i == BUCKETS - 1 => Duration::MAX,
+                i => Duration::from_nanos(1 << i),
+            };
+            (upper, n)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn latency(counts: &[(usize, u64)]) -> Latency {
+        let mut buckets = vec![0; BUCKETS];
+        for &(i, n) in counts {
+            buckets[i] = n;
+        }
+        Latency {
+            total: Duration::ZERO,
+            buckets,
+        }
+    }
+
+    #[test]
+    fn quantiles_are_bucket_upper_bounds() {
+        // 90 calls under 16ns, 9 under 1024ns, and one slower than anything bucketed
+        let l = latency(&[(4, 90), (10, 9), (BUCKETS - 1, 1)]);
+        assert_eq!(l.count(), 100);
+        assert_eq!(l.quantile(0.0), Some(Duration::from_nanos(16)));
+        assert_eq!(l.quantile(0.5), Some(Duration::from_nanos(16)));
+        assert_eq!(l.quantile(0.9), Some(Duration::from_nanos(16)));
+        assert_eq!(l.quantile(0.91), Some(Duration::from_nanos(1024)));
+        assert_eq!(l.quantile(0.99), Some(Duration::from_nanos(1024)));
+        assert_eq!(l.quantile(1.0), Some(Duration::MAX));
+        assert_eq!(l.quantile(2.0), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn no_quantiles_without_calls() {
+        let l = latency(&[]);
+        assert_eq!(l.quantile(0.5), None);
+        assert_eq!(l.mean(), None);
+    }
+
+    #[test]
+    fn durations_land_in_power_of_two_buckets() {
+        let stats = Stats::default();
+        stats.record(Duration::from_nanos(0), false);
+        stats.record(Duration::from_nanos(5), false);
+        stats.record(Duration::from_nanos(8), true);
+        let snapshot = stats.snapshot(PatchState::Default, true);
+        assert_eq!((snapshot.calls, snapshot.panics), (3, 1));
+        let buckets: Vec<u64> = snapshot.latency.buckets().map(|(_, n)| n).collect();
+        assert_eq!(&buckets[..5], &[1, 0, 0, 1, 1]);
+        assert_eq!(snapshot.latency.mean(), Some(Duration::from_nanos(13 / 3)));
+    }
+
+    #[patchable]
+    fn during_unwind() {}
+
+    struct CallOnDrop;
+
+    impl Drop for CallOnDrop {
+        fn drop(&mut self) {
+            during_unwind();
+        }
+    }
+
+    #[test]
+    fn calls_while_unwinding_are_not_panics() {
+        let _ = std::panic::catch_unwind(|| {
+            let _call = CallOnDrop;
+            panic!("unwinding");
+        });
+        let stats = &during_unwind.read().stats()[0];
+        assert_eq!((stats.calls, stats.panics), (1, 0));
+    }
+
+    #[patchable]
+    fn wrapped() {}
+
+    #[test]
+    fn wrapper_keeps_its_stats_when_rebuilt() {
+        let below = wrapped.wrap(|prev| prev()).unwrap();
+        wrapped.wrap(|prev| prev()).unwrap();
+        wrapped();
+        wrapped();
+        wrapped.remove_wrapper(below).unwrap();
+        let stats = &wrapped.read().stats()[0];
+        assert_eq!(stats.calls, 2);
+        wrapped.pop_wrapper().unwrap();
+    }
+}
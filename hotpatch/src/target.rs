@@ -0,0 +1,190 @@
+use std::sync::RwLockWriteGuard;
+use std::time::{Duration, Instant};
+
+use crate::{HotpatchError, HotpatchImportInternal, PatchState, Patchable, SharedDefinition};
+
+// a Patchable with its function type erased
+pub(crate) trait Target: Sync {
+    fn path(&self) -> &'static str;
+    fn sig(&self) -> &'static str;
+    fn state(&self) -> PatchState;
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> Vec<crate::CallStats>;
+    // Ok(None) if the lock is currently held
+    fn try_lock(&self) -> Result<Option<Box<dyn Locked + '_>>, HotpatchError>;
+    fn lock(&self) -> Box<dyn Locked + '_>;
+}
+
+pub(crate) trait Locked {
+    #[cfg(feature = "tracing")]
+    fn path(&self) -> &'static str;
+    fn push(&mut self, def: SharedDefinition) -> SharedDefinition;
+    fn restore(&mut self, def: SharedDefinition) -> SharedDefinition;
+    fn unscope(
+        &mut self,
+        installed: SharedDefinition,
+        previous: SharedDefinition,
+    ) -> Option<SharedDefinition>;
+}
+
+impl<RealType: ?Sized + Send + Sync + 'static> Target for Patchable<RealType> {
+    fn path(&self) -> &'static str {
+        self.read().path
+    }
+    fn sig(&self) -> &'static str {
+        self.read().sig
+    }
+    fn state(&self) -> PatchState {
+        self.read().state()
+    }
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> Vec<crate::CallStats> {
+        self.read().stats()
+    }
+    fn try_lock(&self) -> Result<Option<Box<dyn Locked + '_>>, HotpatchError> {
+        match self.try_write() {
+            Ok(guard) => Ok(Some(Box::new(guard))),
+            Err(HotpatchError::WouldBlock) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    fn lock(&self) -> Box<dyn Locked + '_> {
+        Box::new(self.write())
+    }
+}
+
+impl<RealType: ?Sized + Send + Sync + 'static> Locked
+    for RwLockWriteGuard<'_, HotpatchImportInternal<RealType>>
+{
+    #[cfg(feature = "tracing")]
+    fn path(&self) -> &'static str {
+        self.path
+    }
+    fn push(&mut self, def: SharedDefinition) -> SharedDefinition {
+        (**self).push(def)
+    }
+    fn restore(&mut self, def: SharedDefinition) -> SharedDefinition {
+        (**self).restore(def)
+    }
+    fn unscope(
+        &mut self,
+        installed: SharedDefinition,
+        previous: SharedDefinition,
+    ) -> Option<SharedDefinition> {
+        (**self).unscope(installed, previous)
+    }
+}
+
+pub(crate) fn same_target(a: &dyn Target, b: &dyn Target) -> bool {
+    std::ptr::eq(a as *const dyn Target as *const (), b as *const dyn Target as *const ())
+}
+
+// How long swap_all keeps trying before giving up with WouldBlock
+const SWAP_ALL_TIMEOUT: Duration = Duration::from_secs(1);
+// The longest swap_all sleeps between attempts
+const SWAP_ALL_MAX_BACKOFF: Duration = Duration::from_millis(10);
+
+// Take every lock, or none of them and try again. Holding some locks while
+// waiting on another could deadlock with a call from one target into another.
+// Each definition is swapped in with op, either Locked::push or Locked::restore.
+// A lock that's never released, such as by the calling thread being inside one of
+// the targets, fails with WouldBlock once SWAP_ALL_TIMEOUT has passed.
+pub(crate) fn swap_all(
+    targets: &[&dyn Target],
+    defs: Vec<SharedDefinition>,
+    op: fn(&mut dyn Locked, SharedDefinition) -> SharedDefinition,
+) -> Result<Vec<SharedDefinition>, HotpatchError> {
+    let deadline = Instant::now() + SWAP_ALL_TIMEOUT;
+    let mut backoff = Duration::from_micros(1);
+    let mut retries = 0;
+    loop {
+        let mut locked = Vec::with_capacity(targets.len());
+        for target in targets {
+            match target.try_lock()? {
+                Some(guard) => locked.push(guard),
+                None => break,
+            }
+        }
+        if locked.len() == targets.len() {
+            #[cfg(feature = "tracing")]
+            if retries > 0 {
+                let paths: Vec<_> = locked.iter().map(|guard| guard.path()).collect();
+                event!(DEBUG, ?paths, retries, "took contended locks");
+            }
+            return Ok(locked
+                .iter_mut()
+                .zip(defs)
+                .map(|(guard, def)| op(&mut **guard, def))
+                .collect());
+        }
+        drop(locked);
+        if Instant::now() >= deadline {
+            event!(WARN, retries, "gave up waiting for contended locks");
+            return Err(HotpatchError::WouldBlock);
+        }
+        // locks are usually only held for one call, so try again straight away at first
+        retries += 1;
+        if retries < 16 {
+            std::thread::yield_now();
+        } else {
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(SWAP_ALL_MAX_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::transmute;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::*;
+
+    #[patchable]
+    fn first() -> i32 {
+        1
+    }
+
+    #[patchable]
+    fn second() -> i32 {
+        2
+    }
+
+    type Def = dyn Fn() -> i32 + Send + Sync + 'static;
+
+    fn returning(n: i32) -> SharedDefinition {
+        Definition {
+            ptr: unsafe { transmute::<Box<Def>, Box<FnVoid>>(Box::new(move || n)) },
+            lib: None,
+            state: PatchState::Closure,
+            wrap: None,
+            #[cfg(feature = "stats")]
+            stats: Default::default(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn swaps_every_target_or_none() {
+        let targets: [&dyn Target; 2] = [&first, &second];
+        {
+            let _held = second.write();
+            let start = Instant::now();
+            let defs = vec![returning(10), returning(20)];
+            let result = swap_all(&targets, defs, |l, d| l.push(d));
+            assert!(matches!(result, Err(HotpatchError::WouldBlock)));
+            assert!(start.elapsed() >= SWAP_ALL_TIMEOUT);
+        }
+        // first wasn't swapped either, though its lock was free
+        assert_eq!(first(), 1);
+        assert_eq!(second(), 2);
+
+        let defs = vec![returning(10), returning(20)];
+        let previous = swap_all(&targets, defs, |l, d| l.push(d)).unwrap();
+        assert_eq!((first(), second()), (10, 20));
+        assert!(previous.iter().all(|d| d.state == PatchState::Default));
+        swap_all(&targets, previous, |l, d| l.restore(d)).unwrap();
+        assert_eq!((first(), second()), (1, 2));
+    }
+}
@@ -0,0 +1,29 @@
+// Events and spans for the `tracing` feature. Without it they compile to nothing, and
+// their fields aren't evaluated, so anything only needed for them belongs in the call.
+
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    ($level:ident, $($field:tt)*) => {
+        tracing::event!(target: "hotpatch", tracing::Level::$level, $($field)*)
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($($t:tt)*) => {
+        ()
+    };
+}
+
+// Entered until the returned guard drops
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($level:ident, $($field:tt)*) => {
+        tracing::span!(target: "hotpatch", tracing::Level::$level, $($field)*).entered()
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($t:tt)*) => {
+        ()
+    };
+}
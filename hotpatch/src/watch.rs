@@ -0,0 +1,174 @@
+//! Reload patch libraries automatically when they are rebuilt.
+//!
+//! Requires the `watch` feature.
+//!
+//! ## Example
+//! ```no_run
+//! use hotpatch::watch::Watcher;
+//!
+//! fn main() -> Result<(), hotpatch::HotpatchError> {
+//!     let mut watcher = Watcher::new(|lib, result| match result {
+//!         Ok(report) => println!("reloaded {}: {:?}", lib.display(), report.applied),
+//!         Err(e) => eprintln!("reloading {} failed: {}", lib.display(), e),
+//!     })?;
+//!     watcher.watch("target/debug/libsomething.so")?;
+//!     // cargo build the patch crate, and the host picks it up
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode};
+
+use crate::cache::load;
+use crate::registry::apply;
+use crate::{ApplyReport, CopyOnLoad, HotpatchError};
+
+/// How long a library has to stop changing before it's reloaded, unless set with
+/// [`Watcher::with_delay`](Watcher::with_delay).
+pub const DEFAULT_DELAY: Duration = Duration::from_millis(500);
+
+type Callback = dyn FnMut(&Path, Result<ApplyReport, HotpatchError>) + Send;
+
+/// Watches patch libraries and runs [`apply_library`](crate::apply_library) on them
+/// whenever they change.
+///
+/// Linkers write a library in several steps, so changes are debounced: a library is
+/// only reloaded once it has stopped changing for a while. It's then always loaded
+/// from a copy, as with [`CopyOnLoad::Always`](crate::CopyOnLoad::Always), so the
+/// linker can't overwrite code that is still in use.
+///
+/// Every reload is reported to the callback, from a background thread. The thread
+/// stops when the `Watcher` is dropped.
+pub struct Watcher {
+    inner: Option<RecommendedWatcher>,
+    thread: Option<JoinHandle<()>>,
+    libs: Arc<Mutex<HashSet<PathBuf>>>,
+    dirs: HashMap<PathBuf, usize>, // libraries are watched through their directory
+}
+
+impl Watcher {
+    /// Create a watcher that reports every reload to `callback`.
+    pub fn new<F>(callback: F) -> Result<Self, HotpatchError>
+    where
+        F: FnMut(&Path, Result<ApplyReport, HotpatchError>) + Send + 'static,
+    {
+        Self::with_delay(DEFAULT_DELAY, callback)
+    }
+    /// Like [`new`](Watcher::new), but waits for libraries to stop changing for
+    /// `delay` rather than [`DEFAULT_DELAY`](DEFAULT_DELAY).
+    pub fn with_delay<F>(delay: Duration, callback: F) -> Result<Self, HotpatchError>
+    where
+        F: FnMut(&Path, Result<ApplyReport, HotpatchError>) + Send + 'static,
+    {
+        let (tx, rx) = channel();
+        let inner = notify::watcher(tx, delay).map_err(|source| HotpatchError::Watch { source })?;
+        let libs = Arc::new(Mutex::new(HashSet::new()));
+        let thread_libs = libs.clone();
+        let thread = std::thread::spawn(move || run(rx, thread_libs, Box::new(callback)));
+        Ok(Self {
+            inner: Some(inner),
+            thread: Some(thread),
+            libs,
+            dirs: HashMap::new(),
+        })
+    }
+    /// Start watching a library. It doesn't have to exist yet, but its directory does.
+    ///
+    /// The library isn't loaded until it changes; call
+    /// [`apply_library`](crate::apply_library) as well to load it straight away.
+    pub fn watch<P: AsRef<Path>>(&mut self, lib: P) -> Result<(), HotpatchError> {
+        let (dir, lib) = split(lib.as_ref())?;
+        if !self.libs.lock()?.insert(lib) {
+            return Ok(());
+        }
+        let count = self.dirs.entry(dir.clone()).or_insert(0);
+        if *count == 0 {
+            notify::Watcher::watch(
+                self.inner.as_mut().unwrap(),
+                &dir,
+                RecursiveMode::NonRecursive,
+            )
+            .map_err(|source| HotpatchError::Watch { source })?;
+        }
+        *count += 1;
+        Ok(())
+    }
+    /// Stop watching a library. Patches already applied from it stay in place.
+    pub fn unwatch<P: AsRef<Path>>(&mut self, lib: P) -> Result<(), HotpatchError> {
+        let (dir, lib) = split(lib.as_ref())?;
+        if !self.libs.lock()?.remove(&lib) {
+            return Ok(());
+        }
+        let count = self.dirs.get_mut(&dir).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.dirs.remove(&dir);
+            notify::Watcher::unwatch(self.inner.as_mut().unwrap(), &dir)
+                .map_err(|source| HotpatchError::Watch { source })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // dropping the notify watcher closes the channel, which ends the thread
+        self.inner.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Absolute directory and path of a library, as events are reported with absolute paths
+fn split(lib: &Path) -> Result<(PathBuf, PathBuf), HotpatchError> {
+    let io_error = |source| HotpatchError::Io {
+        path: lib.to_owned(),
+        source,
+    };
+    let name = lib
+        .file_name()
+        .ok_or_else(|| io_error(std::io::ErrorKind::InvalidInput.into()))?;
+    let dir = match lib.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let dir = dir.canonicalize().map_err(io_error)?;
+    let lib = dir.join(name);
+    Ok((dir, lib))
+}
+
+fn run(
+    rx: Receiver<DebouncedEvent>,
+    libs: Arc<Mutex<HashSet<PathBuf>>>,
+    mut callback: Box<Callback>,
+) {
+    for event in rx {
+        let changed = match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Rename(_, path) => path,
+            _ => continue,
+        };
+        let watched = match libs.lock() {
+            Ok(libs) => libs.contains(&changed),
+            Err(_) => return,
+        };
+        if watched {
+            callback(&changed, reload(&changed));
+        }
+    }
+}
+
+fn reload(lib: &Path) -> Result<ApplyReport, HotpatchError> {
+    let lib_name = lib.to_string_lossy();
+    let loaded = load(&lib_name, CopyOnLoad::Always)?;
+    apply(Arc::new(loaded), &lib_name)
+}
@@ -0,0 +1,316 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use variadic_generics::*;
+
+use crate::{
+    Definition, FnVoid, HotpatchError, HotpatchWrap, LoadedLibrary, PatchState, Patchable,
+    SharedDefinition,
+};
+#[cfg(feature = "stats")]
+use crate::Stats;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies one wrapper added by [`wrap`](crate::HotpatchWrap::wrap), to take it back
+/// out with [`remove_wrapper`](crate::Patchable::remove_wrapper).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WrapId(u64);
+
+// What a wrapper definition knows about the definition it delegates to
+pub(crate) struct Wrap {
+    pub(crate) id: WrapId,
+    pub(crate) prev: SharedDefinition,
+    // the export it was loaded from, so loading it again replaces this layer
+    pub(crate) source: Option<String>,
+    // the same wrapper over another definition
+    pub(crate) rewrap: Box<dyn Fn(SharedDefinition) -> Definition + Send + Sync>,
+}
+
+// A wrapper, and what it needs to make a definition over any prev.
+// W is a closure or an exported fn taking the previous definition first.
+pub(crate) struct Wrapper<W, Ret> {
+    id: WrapId,
+    wrapper: Arc<W>,
+    // boxes a Layer<W> as a dyn Fn of the Patchable's type, see HotpatchWrap::wrap
+    erase: fn(Layer<W, Ret>) -> Box<FnVoid>,
+    by: PatchState,
+    lib: Option<Arc<LoadedLibrary>>,
+    source: Option<String>,
+    // kept when rebuilt over another definition
+    #[cfg(feature = "stats")]
+    stats: Arc<Stats>,
+}
+
+impl<W, Ret> Clone for Wrapper<W, Ret> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            wrapper: self.wrapper.clone(),
+            erase: self.erase,
+            by: self.by.clone(),
+            lib: self.lib.clone(),
+            source: self.source.clone(),
+            #[cfg(feature = "stats")]
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<W: Send + Sync + 'static, Ret: 'static> Wrapper<W, Ret> {
+    pub(crate) fn new(
+        wrapper: W,
+        erase: fn(Layer<W, Ret>) -> Box<FnVoid>,
+        by: PatchState,
+        lib: Option<Arc<LoadedLibrary>>,
+        source: Option<String>,
+    ) -> Self {
+        Self {
+            id: WrapId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            wrapper: Arc::new(wrapper),
+            erase,
+            by,
+            lib,
+            source,
+            #[cfg(feature = "stats")]
+            stats: Default::default(),
+        }
+    }
+    pub(crate) fn id(&self) -> WrapId {
+        self.id
+    }
+    pub(crate) fn over(&self, prev: SharedDefinition) -> Definition {
+        let layer = Layer {
+            wrapper: self.wrapper.clone(),
+            prev: prev.clone(),
+            ret: PhantomData,
+        };
+        let this = self.clone();
+        Definition {
+            ptr: (self.erase)(layer),
+            lib: self.lib.clone(),
+            state: PatchState::Wrapped {
+                by: Box::new(self.by.clone()),
+                over: Box::new(prev.state.clone()),
+            },
+            wrap: Some(Wrap {
+                id: self.id,
+                prev,
+                source: self.source.clone(),
+                rewrap: Box::new(move |prev| this.over(prev)),
+            }),
+            #[cfg(feature = "stats")]
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+// A wrapper bound to the definition it delegates to, called as the Patchable's type
+pub(crate) struct Layer<W, Ret> {
+    wrapper: Arc<W>,
+    prev: SharedDefinition,
+    ret: PhantomData<fn() -> Ret>,
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+                impl<W, Ret, $($va_idents,)*> FnOnce<($($va_idents,)*)> for Layer<W, Ret>
+    where
+                W: Fn(&(dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static), $($va_idents,)*) -> Ret,
+                {
+                type Output = Ret;
+                    extern "rust-call" fn call_once(self, args: ($($va_idents,)*)) -> Ret {
+                    self.call(args)
+                }
+                }
+}
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+                impl<W, Ret, $($va_idents,)*> FnMut<($($va_idents,)*)> for Layer<W, Ret>
+    where
+                W: Fn(&(dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static), $($va_idents,)*) -> Ret,
+                {
+                extern "rust-call" fn call_mut(&mut self, args: ($($va_idents,)*)) -> Ret {
+                    self.call(args)
+                }
+                }
+}
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+                impl<W, Ret, $($va_idents,)*> Fn<($($va_idents,)*)> for Layer<W, Ret>
+    where
+                W: Fn(&(dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static), $($va_idents,)*) -> Ret,
+                {
+                #[allow(unused_variables)]
+                extern "rust-call" fn call(&self, args: ($($va_idents,)*)) -> Ret {
+                    let prev = self.prev.upcast::<dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static>();
+                    (self.wrapper)(prev, $(args.$va_indices,)*)
+                }
+                }
+}
+
+/// Public interface for [`before`](HotpatchBefore::before); requires import to use.
+pub trait HotpatchBefore<F, Dummy> {
+    /// [`wrap`](HotpatchWrap::wrap) this functor with one that calls `f` with references to
+    /// the arguements, then calls through to the definition that was current.
+    ///
+    /// ## Example
+    /// ```
+    /// # use hotpatch::*;
+    /// #[patchable]
+    /// fn foo(a: i32) -> i32 { a }
+    ///
+    /// fn main() -> Result<(), HotpatchError> {
+    ///   let logged = foo.before(|a: &i32| println!("foo({})", a))?;
+    ///   assert_eq!(foo(1), 1); // prints foo(1)
+    ///   foo.remove_wrapper(logged)?;
+    ///   Ok(())
+    /// }
+    /// ```
+    fn before(&self, f: F) -> Result<WrapId, HotpatchError>;
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        impl<RealType: ?Sized + Send + Sync + 'static, F, Ret: 'static, $($va_idents: 'static,)*> HotpatchBefore<F, (Ret, $($va_idents,)*)>
+        for Patchable<RealType>
+    where
+        F: Fn($(&$va_idents,)*) + Send + Sync + 'static,
+        RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        {
+            #[allow(non_snake_case)]
+            fn before(&self, f: F) -> Result<WrapId, HotpatchError> {
+            self.wrap(move |prev: &(dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static), $($va_idents: $va_idents,)*| {
+                f($(&$va_idents,)*);
+                prev($($va_idents,)*)
+            })
+            }
+        }
+}
+
+/// Public interface for [`after`](HotpatchAfter::after); requires import to use.
+pub trait HotpatchAfter<F, Dummy> {
+    /// [`wrap`](HotpatchWrap::wrap) this functor with one that calls through to the
+    /// definition that was current, then calls `f` with a reference to what it returned.
+    /// `f` isn't called if it panics.
+    ///
+    /// ## Example
+    /// ```
+    /// # use hotpatch::*;
+    /// #[patchable]
+    /// fn foo(a: i32) -> i32 { a }
+    ///
+    /// fn main() -> Result<(), HotpatchError> {
+    ///   foo.after(|r: &i32| println!("foo returned {}", r))?;
+    ///   assert_eq!(foo(1), 1); // prints foo returned 1
+    ///   foo.pop_wrapper()?;
+    ///   Ok(())
+    /// }
+    /// ```
+    fn after(&self, f: F) -> Result<WrapId, HotpatchError>;
+}
+
+#[cfg(not(doc))]
+va_largesig! { ($va_len:tt), ($($va_idents:ident),*), ($($va_indices:tt),*),
+        impl<RealType: ?Sized + Send + Sync + 'static, F, Ret: 'static, $($va_idents: 'static,)*> HotpatchAfter<F, (Ret, $($va_idents,)*)>
+        for Patchable<RealType>
+    where
+        F: Fn(&Ret) + Send + Sync + 'static,
+        RealType: Fn($($va_idents,)*) -> Ret + Send + Sync + 'static,
+        {
+            #[allow(non_snake_case)]
+            fn after(&self, f: F) -> Result<WrapId, HotpatchError> {
+            self.wrap(move |prev: &(dyn Fn($($va_idents,)*) -> Ret + Send + Sync + 'static), $($va_idents: $va_idents,)*| {
+                let ret = prev($($va_idents,)*);
+                f(&ret);
+                ret
+            })
+            }
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::transmute;
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::*;
+
+    #[patchable]
+    fn stacked(a: i32) -> i32 {
+        a
+    }
+
+    #[test]
+    fn remove_wrapper_from_middle_of_stack() {
+        let double = stacked.wrap(|prev, a| prev(a) * 2).unwrap();
+        let _plus = stacked.wrap(|prev, a| prev(a) + 1).unwrap();
+        let _neg = stacked.wrap(|prev, a| -prev(a)).unwrap();
+        assert_eq!(stacked(1), -3);
+        stacked.remove_wrapper(double).unwrap();
+        assert_eq!(stacked(1), -2);
+        stacked.pop_wrapper().unwrap();
+        assert_eq!(stacked(1), 2);
+        stacked.pop_wrapper().unwrap();
+        assert_eq!(stacked(1), 1);
+        assert!(matches!(stacked.pop_wrapper(), Err(HotpatchError::NotWrapped)));
+    }
+
+    #[patchable]
+    fn advised(a: i32) -> i32 {
+        a + 1
+    }
+
+    #[test]
+    fn before_and_after_see_arguments_and_result() {
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+        static RETURNED: AtomicUsize = AtomicUsize::new(0);
+        let before = advised.before(|a: &i32| SEEN.store(*a as usize, Ordering::Relaxed)).unwrap();
+        advised.after(|r: &i32| RETURNED.store(*r as usize, Ordering::Relaxed)).unwrap();
+        assert_eq!(advised(4), 5);
+        assert_eq!(SEEN.load(Ordering::Relaxed), 4);
+        assert_eq!(RETURNED.load(Ordering::Relaxed), 5);
+        advised.remove_wrapper(before).unwrap();
+        advised.pop_wrapper().unwrap();
+        assert_eq!(advised(1), 2);
+    }
+
+    #[patchable]
+    fn reloaded(a: i32) -> i32 {
+        a
+    }
+
+    type Prev = dyn Fn(i32) -> i32 + Send + Sync + 'static;
+
+    // as a #[patch(wrap)] export would be loaded
+    fn exported(add: i32) -> Wrapper<impl Fn(&Prev, i32) -> i32, i32> {
+        Wrapper::new(
+            move |prev: &Prev, a| prev(a) + add,
+            |layer| unsafe { transmute::<Box<Prev>, Box<FnVoid>>(Box::new(layer)) },
+            PatchState::Closure,
+            None,
+            Some("reloaded_export".to_owned()),
+        )
+    }
+
+    #[test]
+    fn reloading_an_export_replaces_its_layer() {
+        let mut inner = reloaded.write();
+        let def = exported(10).over(inner.current());
+        inner.push(def.into());
+        drop(inner);
+        reloaded.wrap(|prev, a| prev(a) * 2).unwrap();
+        assert_eq!(reloaded(1), 22);
+        let mut inner = reloaded.write();
+        let def = exported(100).over(inner.current());
+        inner.push(def.into());
+        drop(inner);
+        // in place of the first, under the wrapper added since
+        assert_eq!(reloaded(1), 202);
+        reloaded.pop_wrapper().unwrap();
+        reloaded.pop_wrapper().unwrap();
+        assert_eq!(reloaded(1), 1);
+    }
+}
@@ -0,0 +1,70 @@
+use proc_macro::TokenStream;
+use quote::{quote, ToTokens};
+use syn::{Data, DeriveInput, Fields};
+
+pub fn derive(mut input: DeriveInput) -> TokenStream {
+    let name = input.ident.clone();
+    // a change of repr changes the layout even if size and offsets happen to match
+    let reprs: Vec<String> = input
+        .attrs
+        .iter()
+        .filter(|a| a.path.is_ident("repr"))
+        .map(|a| a.tokens.to_string())
+        .collect();
+    let reprs = reprs.join(" ");
+
+    let members = match &input.data {
+        Data::Struct(data) => fields(&data.fields, true),
+        Data::Union(data) => fields(&Fields::Named(data.fields.clone()), true),
+        Data::Enum(data) => data
+            .variants
+            .iter()
+            .map(|v| {
+                let variant = v.ident.to_string();
+                let members = fields(&v.fields, false);
+                quote! { .variant(#variant) #members }
+            })
+            .collect(),
+    };
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(syn::parse_quote!(hotpatch::HotpatchAbi));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    TokenStream::from(quote! {
+        impl #impl_generics hotpatch::HotpatchAbi for #name #ty_generics #where_clause {
+            const FINGERPRINT: u64 = hotpatch::abi::Fingerprint::new(stringify!(#name))
+                .str(#reprs)
+                .layout::<Self>()
+                #members
+                .finish();
+        }
+    })
+}
+
+// Offsets are only stable for structs and unions
+fn fields(fields: &Fields, offsets: bool) -> proc_macro2::TokenStream {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let member = match &field.ident {
+                Some(ident) => ident.to_token_stream(),
+                None => syn::Index::from(i).to_token_stream(),
+            };
+            let text = member.to_string();
+            let ty = &field.ty;
+            if offsets {
+                quote! {
+                    .field(#text, core::mem::offset_of!(Self, #member), <#ty as hotpatch::HotpatchAbi>::FINGERPRINT)
+                }
+            } else {
+                quote! {
+                    .member(#text, <#ty as hotpatch::HotpatchAbi>::FINGERPRINT)
+                }
+            }
+        })
+        .collect()
+}
+
@@ -2,18 +2,25 @@ use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::quote;
 use quote::ToTokens;
+use syn::spanned::Spanned;
+use syn::visit_mut::VisitMut;
 use syn::{FnArg::Typed, Ident, ItemFn, ReturnType::Type};
 
-use crate::EXPORTNUM;
+use crate::{abi_fingerprint, export_entry, export_ident, original_slot, registry_entry, snake_case, Options};
 
-pub fn patchable(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
+pub fn patchable(fn_item: ItemFn, options: Options) -> TokenStream {
+    if fn_item.sig.generics.type_params().next().is_some() || !options.instantiate.is_empty() {
+        return patchable_generic(fn_item, options);
+    }
+    let on_panic = options.on_panic();
+    let modpath = options.modpath;
     let (fargs, output_type, mut fn_name, sigtext, mut item) = gather_info(fn_item);
 
     if !cfg!(feature = "allow-main") && !cfg!(feature = "redirect-main") && fn_name == "main" {
         fn_name.span().unwrap().error("Attempted to set main as patchable")
 	    .note("calling main.hotpatch() would cause a deadlock")
-	    .help("enable the 'allow-main' feature if you're using #[main] or #[start]")
-	    .help("enable the 'redirect-main' feature if you actually want main to be patchable (requires unsafe and nightly, read the docs on force functions)")
+	    .help("enable the 'allow-main' feature if you're using #![no_main] with your own entry point")
+	    .help("enable the 'redirect-main' feature if you actually want main to be patchable (requires unsafe and #![no_main], read the docs on force functions)")
 	    .emit();
         return TokenStream::new();
     }
@@ -39,12 +46,19 @@ pub fn patchable(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
     let item_name = fn_name.clone();
     fn_name = Ident::new("__hotpatch_internal_fn_mangle_name", Span::call_site());
     item.sig.ident = fn_name.clone();
+    let ptr = fn_ptr(quote! { #fn_name }, &item);
 
     let redirected_main = if cfg!(feature = "redirect-main") && item_name == "main" {
+        // the binary is #![no_main], and the C runtime calls this instead
         quote! {
-            #[main]
-            fn __hotpatch_redirect_main() -> #output_type {
-            main()
+            #[cfg(not(test))]
+            #[export_name = "main"]
+            extern "C" fn __hotpatch_redirect_main(_: std::os::raw::c_int, _: *const *const std::os::raw::c_char) -> std::os::raw::c_int {
+            let code = std::process::Termination::report(main());
+            // an ExitCode can't be read back as a number, but any made outside std comes from a u8
+            (0..=u8::MAX)
+                .find(|&c| code == std::process::ExitCode::from(c))
+                .map_or(1, std::os::raw::c_int::from)
             }
         }
     } else {
@@ -52,7 +66,7 @@ pub fn patchable(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
     };
 
     let mname = match modpath {
-        Some(mpath) => (quote! {concat!("::", #mpath)}),
+        Some(mpath) => quote! {concat!("::", #mpath)},
         None => {
             quote! {
                 concat!(module_path!(), "::", stringify!(#item_name))
@@ -60,6 +74,9 @@ pub fn patchable(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
         }
     };
 
+    let registered = registry_entry(&quote! { #item_name });
+    let abi = abi_fingerprint(&fargs, &output_type);
+
     TokenStream::from(quote! {
     #docitem
     #[cfg(not(doc))]
@@ -68,24 +85,26 @@ pub fn patchable(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
         || {
         #[inline(always)]
         #item
-            hotpatch::Patchable::__new_internal(Box::new(#fn_name) as Box<dyn Fn#fargs -> #output_type + Send + Sync + 'static>,
+            hotpatch::Patchable::__new_internal(Box::new(#ptr) as Box<dyn Fn#fargs -> #output_type + Send + Sync + 'static>,
                             #mname,
-                            #sigtext)
+                            #sigtext,
+                            #abi,
+                            #on_panic)
         });
+    #registered
     #redirected_main
     })
 }
 
-pub fn patch(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
-    let (fargs, output_type, fn_name, sigtext, mut item) = gather_info(fn_item);
-
-    let exnum;
-    {
-        // scope is used so EXPORTNUM is unlocked faster
-        let mut r = EXPORTNUM.write().unwrap();
-        exnum = *r;
-        *r += 1;
+pub fn patch(fn_item: ItemFn, options: Options) -> TokenStream {
+    if options.wrap {
+        return patch_wrap(fn_item, options);
+    }
+    if fn_item.sig.generics.type_params().next().is_some() || !options.instantiate.is_empty() {
+        return patch_generic(fn_item, options);
     }
+    let modpath = options.modpath;
+    let (fargs, output_type, fn_name, sigtext, mut item) = gather_info(fn_item);
 
     item.attrs.append(
         &mut syn::parse2::<syn::ItemStruct>(quote! {
@@ -93,7 +112,7 @@ pub fn patch(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
         /// ---
         /// ## Hotpatch
         /// This item is a [`#[patch]`](hotpatch::patch). It will silently define a public static
-        /// symbol `__HOTPATCH_EXPORT_N` for use in shared object files. See the
+        /// `__HOTPATCH_EXPORT_<hash>`, listed in the library's manifest for use in shared object files. See the
         /// [Hotpatch Documentation](hotpatch) for more information.
         struct Dummy {}
         })
@@ -101,10 +120,12 @@ pub fn patch(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
         .attrs,
     );
 
-    let hotpatch_name = Ident::new(&format!("__HOTPATCH_EXPORT_{}", exnum), Span::call_site());
+    let hotpatch_name = export_ident(modpath.as_deref().unwrap_or(&fn_name.to_string()), &fn_name, &sigtext);
+    let (slot, original) = original_slot(&hotpatch_name, &mut item.block, &fargs, &output_type);
+    let ptr = fn_ptr(quote! { #fn_name }, &item);
 
     let mname = match modpath {
-        Some(mpath) => (quote! {concat!("::", #mpath)}),
+        Some(mpath) => quote! {concat!("::", #mpath)},
         None => {
             quote! {
                 concat!(module_path!(), "::", stringify!(#fn_name))
@@ -112,17 +133,412 @@ pub fn patch(fn_item: ItemFn, modpath: Option<String>) -> TokenStream {
         }
     };
 
+    let entry = export_entry(&hotpatch_name);
+    let abi = abi_fingerprint(&fargs, &output_type);
+
     TokenStream::from(quote! {
     #item
+    #slot
     #[doc(hidden)]
-    #[no_mangle]
     pub static #hotpatch_name: hotpatch::HotpatchExport<fn#fargs -> #output_type> =
-            hotpatch::HotpatchExport::__new(#fn_name,
+            hotpatch::HotpatchExport::__new(#ptr,
                         #mname,
-                        #sigtext);
+                        #sigtext,
+                        || #abi,
+                        false,
+                        #original);
+    #entry
+    })
+}
+
+// The export takes the previous definition as a `&dyn Fn` of the Patchable's type,
+// and the function is called with it through a shim, so that the first arguement
+// can be declared as any reference it coerces to.
+fn patch_wrap(fn_item: ItemFn, options: Options) -> TokenStream {
+    if fn_item.sig.generics.type_params().next().is_some() || !options.instantiate.is_empty() {
+        fn_item.sig.generics.span().unwrap().error("wrap doesn't support generic functions").emit();
+        return TokenStream::new();
+    }
+    if let Some(a) = &fn_item.sig.asyncness {
+        a.span().unwrap().error("wrap doesn't support async functions").emit();
+        return TokenStream::new();
+    }
+    if !matches!(fn_item.sig.inputs.first(), Some(Typed(_))) {
+        fn_item.sig.span().unwrap().error("#[patch(wrap)] takes the previous definition as its first arguement")
+            .help("such as fn foo(prev: &dyn Fn(i32) -> i32, a: i32) -> i32")
+            .emit();
+        return TokenStream::new();
+    }
+    let modpath = options.modpath;
+    // the signature of the Patchable, without prev
+    let mut patched = fn_item.clone();
+    patched.sig.inputs = fn_item.sig.inputs.iter().skip(1).cloned().collect();
+    let (fargs, output_type, fn_name, sigtext, _) = gather_info(patched);
+
+    let mut item = fn_item;
+    item.attrs.append(
+        &mut syn::parse2::<syn::ItemStruct>(quote! {
+        ///
+        /// ---
+        /// ## Hotpatch
+        /// This item is a [`#[patch(wrap)]`](hotpatch::patch). It will silently define a public static
+        /// `__HOTPATCH_EXPORT_<hash>`, listed in the library's manifest for use in shared object files. See the
+        /// [Hotpatch Documentation](hotpatch) for more information.
+        struct Dummy {}
+        })
+        .unwrap()
+        .attrs,
+    );
+
+    let hotpatch_name = export_ident(modpath.as_deref().unwrap_or(&fn_name.to_string()), &fn_name, &sigtext);
+    let (slot, original) = original_slot(&hotpatch_name, &mut item.block, &fargs, &output_type);
+    let (names, types): (Vec<Ident>, Vec<&syn::Type>) = item
+        .sig
+        .inputs
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(i, input)| match input {
+            Typed(t) => Some((Ident::new(&format!("__hotpatch_arg_{}", i), Span::call_site()), &*t.ty)),
+            _ => None,
+        })
+        .unzip();
+    let prev = quote! { &(dyn Fn #fargs -> #output_type + Send + Sync + 'static) };
+
+    let mname = match modpath {
+        Some(mpath) => quote! { concat!("::", #mpath) },
+        None => {
+            quote! {
+                concat!(module_path!(), "::", stringify!(#fn_name))
+            }
+        }
+    };
+
+    let entry = export_entry(&hotpatch_name);
+    let abi = abi_fingerprint(&fargs, &output_type);
+
+    TokenStream::from(quote! {
+    #item
+    #slot
+    #[doc(hidden)]
+    pub static #hotpatch_name: hotpatch::HotpatchExport<fn(#prev, #(#types),*) -> #output_type> =
+            hotpatch::HotpatchExport::__new(|__hotpatch_prev: #prev, #(#names: #types),*| #fn_name(__hotpatch_prev, #(#names),*),
+                        #mname,
+                        #sigtext,
+                        || #abi,
+                        true,
+                        #original);
+    #entry
+    })
+}
+
+// One Patchable per instantiation is kept in a module named after the function,
+// and a generic wrapper dispatches to them through a hidden trait implemented
+// for each tuple of instantiated types.
+fn patchable_generic(fn_item: ItemFn, options: Options) -> TokenStream {
+    let instances = match instances(&fn_item, &options) {
+        Some(instances) => instances,
+        None => return TokenStream::new(),
+    };
+    let item_name = fn_item.sig.ident.clone();
+    let vis = fn_item.vis.clone();
+    let on_panic = options.on_panic();
+    let params: Vec<Ident> = fn_item.sig.generics.type_params().map(|t| t.ident.clone()).collect();
+    let binder = lifetime_binder(&fn_item.sig.generics);
+
+    let mut docitem = fn_item.clone();
+    let note = format!(
+        " Each instantiation has its own [`Patchable`](hotpatch::Patchable) in the `{}` module.",
+        item_name
+    );
+    docitem.attrs.append(
+        &mut syn::parse2::<syn::ItemStruct>(quote! {
+            ///
+            /// ---
+            /// ## Hotpatch
+            /// **Warning**: This item is [`#[patchable]`](hotpatch::patchable). Runtime behavior may not
+            /// follow the source implementation. See the
+            /// [Hotpatch Documentation](hotpatch) for more information.
+            #[doc = #note]
+        #[cfg(doc)]
+            struct Dummy {}
+        })
+        .unwrap()
+        .attrs,
+    );
+
+    let mut item = fn_item.clone();
+    let fn_name = Ident::new(&format!("__hotpatch_internal_generic_{}", item_name), Span::call_site());
+    item.sig.ident = fn_name.clone();
+    item.vis = syn::Visibility::Inherited;
+
+    // The trait signature is the original with every type parameter replaced by `Self::T`
+    let (arg_names, trait_sig) = renamed_sig(&fn_item.sig, &params, |p| quote! { Self::#p });
+
+    let mut statics = vec![];
+    let mut impls = vec![];
+    for instance in instances {
+        let types: Vec<&syn::Type> = instance.iter().map(|(_, t)| t).collect();
+        let (fargs, output_type, _, sigtext, _) = gather_info(instantiated(&fn_item, &instance));
+        let inst_text = types
+            .iter()
+            .map(|t| t.to_token_stream().to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        let inst_name = snake_case(&inst_text);
+        let inst_name = syn::parse_str::<Ident>(&inst_name)
+            .unwrap_or_else(|_| Ident::new_raw(&inst_name, Span::call_site()));
+        let mname = match &options.modpath {
+            Some(mpath) => quote! { concat!("::", #mpath, "::<", #inst_text, ">") },
+            None => quote! { concat!(module_path!(), "::<", #inst_text, ">") },
+        };
+        let abi = abi_fingerprint(&fargs, &output_type);
+        statics.push(quote! {
+            #[allow(non_upper_case_globals)]
+            pub static #inst_name: hotpatch::Patchable<dyn #binder Fn#fargs -> #output_type + Send + Sync + 'static> = hotpatch::Patchable::__new(
+                || {
+                    hotpatch::Patchable::__new_internal(Box::new(super::#fn_name::<#(#types),*>) as Box<dyn #binder Fn#fargs -> #output_type + Send + Sync + 'static>,
+                                    #mname,
+                                    #sigtext,
+                                    #abi,
+                                    #on_panic)
+                });
+        });
+        statics.push(registry_entry(&quote! { #inst_name }));
+        let sig = &trait_sig;
+        impls.push(quote! {
+            impl Instance for (#(#types,)*) {
+                #(type #params = #types;)*
+                #[inline(always)]
+                #sig {
+                    (#inst_name)(#(#arg_names),*)
+                }
+            }
+        });
+    }
+
+    let (_, wrapper_sig) = renamed_sig(&fn_item.sig, &[], |p| quote! { #p });
+    let mut wrapper_sig = wrapper_sig;
+    wrapper_sig.ident = item_name.clone();
+    wrapper_sig
+        .generics
+        .make_where_clause()
+        .predicates
+        .push(syn::parse_quote! { (#(#params,)*): #item_name::Instance<#(#params = #params),*> });
+    let trait_sig = &trait_sig;
+
+    TokenStream::from(quote! {
+    #docitem
+    #[cfg(not(doc))]
+    #item
+    #[cfg(not(doc))]
+    #[inline(always)]
+    #vis #wrapper_sig {
+        <(#(#params,)*) as #item_name::Instance>::__hotpatch_call(#(#arg_names),*)
+    }
+    #[cfg(not(doc))]
+    #vis mod #item_name {
+        use super::*;
+        #[doc(hidden)]
+        pub trait Instance {
+            #(type #params;)*
+            #trait_sig;
+        }
+        #(#statics)*
+        #(#impls)*
+    }
     })
 }
 
+fn patch_generic(fn_item: ItemFn, options: Options) -> TokenStream {
+    let instances = match instances(&fn_item, &options) {
+        Some(instances) => instances,
+        None => return TokenStream::new(),
+    };
+    let fn_name = fn_item.sig.ident.clone();
+    let binder = lifetime_binder(&fn_item.sig.generics);
+
+    let exports: Vec<_> = instances
+        .iter()
+        .map(|instance| {
+            let types: Vec<&syn::Type> = instance.iter().map(|(_, t)| t).collect();
+            let (fargs, output_type, _, sigtext, _) = gather_info(instantiated(&fn_item, instance));
+            let inst_text = types
+                .iter()
+                .map(|t| t.to_token_stream().to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            let key = format!(
+                "{}::<{}>",
+                options.modpath.clone().unwrap_or_else(|| fn_name.to_string()),
+                inst_text
+            );
+            let hotpatch_name = export_ident(&key, &fn_name, &sigtext);
+            let mname = match &options.modpath {
+                Some(mpath) => quote! { concat!("::", #mpath, "::<", #inst_text, ">") },
+                None => quote! {
+                    concat!(module_path!(), "::", stringify!(#fn_name), "::<", #inst_text, ">")
+                },
+            };
+            let entry = export_entry(&hotpatch_name);
+            let abi = abi_fingerprint(&fargs, &output_type);
+            quote! {
+            #[doc(hidden)]
+            pub static #hotpatch_name: hotpatch::HotpatchExport<#binder fn#fargs -> #output_type> =
+                    hotpatch::HotpatchExport::__new(#fn_name::<#(#types),*>,
+                                #mname,
+                                #sigtext,
+                                || #abi,
+                                false,
+                                None);
+            #entry
+            }
+        })
+        .collect();
+
+    let mut item = fn_item;
+    item.attrs.append(
+        &mut syn::parse2::<syn::ItemStruct>(quote! {
+        ///
+        /// ---
+        /// ## Hotpatch
+        /// This item is a [`#[patch]`](hotpatch::patch). It will silently define a public static
+        /// `__HOTPATCH_EXPORT_<hash>` for each instantiation, listed in the library's manifest for use in shared object files. See the
+        /// [Hotpatch Documentation](hotpatch) for more information.
+        struct Dummy {}
+        })
+        .unwrap()
+        .attrs,
+    );
+
+    TokenStream::from(quote! {
+    #item
+    #(#exports)*
+    })
+}
+
+// Checks that every instantiation binds each type parameter exactly once,
+// returning the bindings in the order the parameters are declared.
+fn instances(item: &ItemFn, options: &Options) -> Option<Vec<Vec<(Ident, syn::Type)>>> {
+    let params: Vec<Ident> = item.sig.generics.type_params().map(|t| t.ident.clone()).collect();
+    if options.instantiate.is_empty() {
+        item.sig.generics.span().unwrap().error("Can't hotpatch a generic function without knowing its instantiations")
+            .help("list the types to generate a Patchable for, such as #[patchable(instantiate(T = u32, T = String))]")
+            .emit();
+        return None;
+    }
+    if let Some(a) = &item.sig.asyncness {
+        a.span().unwrap().error("Can't hotpatch a function that is both generic and async").emit();
+        return None;
+    }
+    if let Some(c) = item.sig.generics.const_params().next() {
+        c.span().unwrap().error("Can't hotpatch a function with const generics").emit();
+        return None;
+    }
+    let mut ok = true;
+    let instances = options
+        .instantiate
+        .iter()
+        .map(|instance| {
+            for (ident, _) in instance {
+                if !params.contains(ident) {
+                    ident.span().unwrap().error(format!("{} is not a type parameter of this function", ident)).emit();
+                    ok = false;
+                }
+            }
+            params
+                .iter()
+                .filter_map(|p| {
+                    let mut found = instance.iter().filter(|(ident, _)| ident == p);
+                    let binding = found.next();
+                    if binding.is_none() || found.next().is_some() {
+                        p.span().unwrap().error(format!("Each instantiation must give exactly one type for {}", p)).emit();
+                        ok = false;
+                    }
+                    binding.cloned()
+                })
+                .collect()
+        })
+        .collect();
+    if ok {
+        Some(instances)
+    } else {
+        None
+    }
+}
+
+// The function with its type parameters replaced by the instantiated types
+fn instantiated(item: &ItemFn, instance: &[(Ident, syn::Type)]) -> ItemFn {
+    let mut item = item.clone();
+    item.sig.generics.params = item
+        .sig
+        .generics
+        .params
+        .into_iter()
+        .filter(|p| !matches!(p, syn::GenericParam::Type(_)))
+        .collect();
+    item.sig.generics.where_clause = None;
+    let mut sub = Substitute(instance.iter().map(|(i, t)| (i.clone(), t.to_token_stream())).collect());
+    for input in item.sig.inputs.iter_mut() {
+        sub.visit_fn_arg_mut(input);
+    }
+    sub.visit_return_type_mut(&mut item.sig.output);
+    item
+}
+
+// A copy of `sig` with arguements renamed to bindable idents and the given type parameters
+// substituted, used for the dispatching wrapper and trait
+fn renamed_sig(
+    sig: &syn::Signature,
+    params: &[Ident],
+    replacement: impl Fn(&Ident) -> proc_macro2::TokenStream,
+) -> (Vec<Ident>, syn::Signature) {
+    let mut sig = sig.clone();
+    let mut names = vec![];
+    for (i, input) in sig.inputs.iter_mut().enumerate() {
+        if let Typed(t) = input {
+            let arg = Ident::new(&format!("__hotpatch_arg_{}", i), Span::call_site());
+            *t.pat = syn::parse2::<syn::Pat>(quote! { #arg }).unwrap();
+            names.push(arg);
+        }
+    }
+    if !params.is_empty() {
+        sig.ident = Ident::new("__hotpatch_call", Span::call_site());
+        // lifetimes aren't instantiated, so the trait method stays generic over them
+        sig.generics.params = sig
+            .generics
+            .params
+            .into_iter()
+            .filter(|p| matches!(p, syn::GenericParam::Lifetime(_)))
+            .collect();
+        sig.generics.where_clause = None;
+        let mut sub = Substitute(params.iter().map(|p| (p.clone(), replacement(p))).collect());
+        for input in sig.inputs.iter_mut() {
+            sub.visit_fn_arg_mut(input);
+        }
+        sub.visit_return_type_mut(&mut sig.output);
+    }
+    (names, sig)
+}
+
+struct Substitute(Vec<(Ident, proc_macro2::TokenStream)>);
+
+impl VisitMut for Substitute {
+    fn visit_type_mut(&mut self, ty: &mut syn::Type) {
+        if let syn::Type::Path(p) = ty {
+            if p.qself.is_none() {
+                if let Some(ident) = p.path.get_ident() {
+                    if let Some((_, t)) = self.0.iter().find(|(i, _)| i == ident) {
+                        *ty = syn::parse2(t.clone()).unwrap();
+                        return;
+                    }
+                }
+            }
+        }
+        syn::visit_mut::visit_type_mut(self, ty);
+    }
+}
+
 fn gather_info(item: ItemFn) -> (syn::Type, syn::Type, Ident, String, ItemFn) {
     let fn_name = item.sig.ident.clone();
     let output_type = if let Type(_, t) = &item.sig.output {
@@ -137,19 +553,36 @@ fn gather_info(item: ItemFn) -> (syn::Type, syn::Type, Ident, String, ItemFn) {
     let mut ts = proc_macro2::TokenStream::new();
     output_type.to_tokens(&mut ts);
 
+    // async functions are stored by their boxed future
+    let output_type = if item.sig.asyncness.is_some() {
+        syn::parse2::<syn::Type>(quote! {
+            hotpatch::BoxFuture<#output_type>
+        })
+        .unwrap()
+    } else {
+        output_type
+    };
+
     let sigtext = format!(
-        "fn({}) -> {}",
+        "{}fn({}) -> {}",
+        if item.sig.asyncness.is_some() { "async " } else { "" },
         item.sig
             .inputs
             .clone()
             .into_iter()
-            .map(|input| {
-                if let syn::FnArg::Typed(t) = input {
+            .map(|input| match input {
+                syn::FnArg::Typed(t) => {
                     let mut ts = proc_macro2::TokenStream::new();
                     t.ty.to_tokens(&mut ts);
                     ts.to_string()
-                } else {
-                    todo!() // give an error or something
+                }
+                syn::FnArg::Receiver(r) => {
+                    r.span()
+                        .unwrap()
+                        .error("self parameter is only allowed in methods")
+                        .help("place #[patchable] or #[patch] on the surrounding impl block instead")
+                        .emit();
+                    String::new()
                 }
             })
             .collect::<Vec<String>>()
@@ -177,3 +610,40 @@ fn gather_info(item: ItemFn) -> (syn::Type, syn::Type, Ident, String, ItemFn) {
 
     (fargs, output_type, fn_name, sigtext, item)
 }
+
+// `for<'a, ..>` over the lifetime parameters of a function, so its stored signature
+// can name them. Empty if there aren't any.
+fn lifetime_binder(generics: &syn::Generics) -> proc_macro2::TokenStream {
+    let lifetimes: Vec<&syn::Lifetime> = generics.lifetimes().map(|l| &l.lifetime).collect();
+    if lifetimes.is_empty() {
+        quote! {}
+    } else {
+        quote! { for<#(#lifetimes),*> }
+    }
+}
+
+// Something callable as `fn_name` with the stored signature. Async functions are
+// wrapped in a non-capturing closure which boxes the future, so this can still
+// coerce to a function pointer.
+fn fn_ptr(fn_name: proc_macro2::TokenStream, item: &ItemFn) -> proc_macro2::TokenStream {
+    if item.sig.asyncness.is_none() {
+        return fn_name;
+    }
+    let output_type = match &item.sig.output {
+        Type(_, t) => quote! { #t },
+        syn::ReturnType::Default => quote! { () },
+    };
+    let (names, types): (Vec<Ident>, Vec<&syn::Type>) = item
+        .sig
+        .inputs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, input)| match input {
+            Typed(t) => Some((Ident::new(&format!("__hotpatch_arg_{}", i), Span::call_site()), &*t.ty)),
+            _ => None,
+        })
+        .unzip();
+    quote! {
+        |#(#names: #types),*| Box::pin(#fn_name(#(#names),*)) as hotpatch::BoxFuture<#output_type>
+    }
+}
@@ -3,44 +3,49 @@ use proc_macro2::Span;
 use quote::quote;
 use quote::ToTokens;
 use syn::{FnArg::Typed, Ident, ImplItemConst, ImplItemMethod, ItemImpl, ReturnType::Type};
-use std::sync::RwLock;
 use syn::spanned::Spanned;
+use syn::visit_mut::VisitMut;
 
-use crate::EXPORTNUM;
-lazy_static::lazy_static! {
-    static ref WRAPPER_NUM: RwLock<usize> = RwLock::new(0);
-}
+use crate::{abi_fingerprint, export_entry, export_ident, original_slot, registry_entry, snake_case, Options};
 
-pub fn patchable(mut fn_item: ItemImpl, modpath: Option<String>) -> TokenStream {
+pub fn patchable(mut fn_item: ItemImpl, options: Options) -> TokenStream {
+    if let Some((ident, _)) = options.instantiate.first().and_then(|i| i.first()) {
+        ident.span().unwrap().error("instantiate(..) is only supported on free functions").emit();
+        return TokenStream::new();
+    }
+    let on_panic = options.on_panic();
+    let modpath = options.modpath;
     let mut tt = proc_macro2::TokenStream::new();
     fn_item.self_ty.to_tokens(&mut tt);
     let self_ty = fn_item.self_ty.clone();
     let impl_name = tt.to_string();
+    let trait_ident = trait_ident(&fn_item);
+    let trait_path = fn_item.trait_.as_ref().map(|(_, path, _)| path.clone());
+
+    // Trait impls can't hold anything the trait doesn't declare, so the
+    // Patchables and original bodies go into a seperate inherent impl
+    let mut inherent_items = vec![];
     
     fn_item.items = fn_item
         .items
         .drain(..)
-        .map(|item| {
+        .flat_map(|item| {
             match item {
+                syn::ImplItem::Method(m) if m.sig.asyncness.is_some() => {
+                    async_error(&m);
+                    vec![syn::ImplItem::Method(m)]
+                }
                 syn::ImplItem::Method(m) => {
-                    let (mut fargs, mut output_type, mut item, mut fn_name, sigtext) = gather_info(m);
+                    let (mut fargs, mut output_type, mut item, mut fn_name, sigtext, has_receiver) = gather_info(m);
 
-		    let wrapper_num;
-		    {
-			// scope is used so EXPORTNUM is unlocked faster
-			let mut r = WRAPPER_NUM.write().unwrap();
-			wrapper_num = *r;
-			*r += 1;
-		    }
-		    
 		    // transform arguements from Self notation to concrete type (only in inetermediate variables)
 		    if let syn::Type::Tuple(ref mut t) = fargs {
 			for farg in t.elems.iter_mut() {
-			    transform_self(&impl_name, farg);
+			    transform_self(&self_ty, trait_path.as_ref(), farg);
 			}
 		    }
 		    // same but for return value
-		    transform_self(&impl_name, &mut output_type);
+		    transform_self(&self_ty, trait_path.as_ref(), &mut output_type);
 		    
                     let vis = item.vis.clone(); // pass through pub
                     let mut docitem = item.clone();
@@ -59,80 +64,163 @@ pub fn patchable(mut fn_item: ItemImpl, modpath: Option<String>) -> TokenStream
                         .attrs,
                     );
                     let item_name = fn_name.clone();
-                    fn_name = Ident::new(&format!("__hotpatch_internal_staticwrap_{}", wrapper_num), Span::call_site());
+                    // methods keep their name for `obj.method()`, so the Patchable lives next to them
+                    let patchable_name = if let Some(t) = &trait_ident {
+                        Ident::new(&format!("{}_{}_patchable", snake_case(&t.to_string()), item_name), item_name.span())
+                    } else if has_receiver {
+                        Ident::new(&format!("{}_patchable", item_name), item_name.span())
+                    } else {
+                        item_name.clone()
+                    };
+                    if patchable_name != item_name {
+                        let note = format!(" The underlying [`Patchable`](hotpatch::Patchable) is `{}::{}`.", impl_name.replace(" ", ""), patchable_name);
+                        docitem.attrs.push(syn::parse_quote! { #[doc = #note] });
+                    }
+                    let mut forward_item = if trait_ident.is_some() || has_receiver {
+                        Some(forwarding_method(&item, &item_name, &patchable_name))
+                    } else {
+                        None
+                    };
+                    // named after the Patchable, which is unique within the type, so the name
+                    // doesn't depend on expansion order
+                    fn_name = Ident::new(&format!("__hotpatch_internal_staticwrap_{}", patchable_name), Span::call_site());
                     item.sig.ident = fn_name.clone();
+		    let fn_key = match &trait_ident {
+			Some(t) => format!("<{} as {}>", impl_name, t),
+			None => impl_name.clone(),
+		    };
 		    let mname = match &modpath {
 			Some(mpath) => 
-			    format!("!__associated_fn:{}:{}", impl_name, mpath),
+			    format!("!__associated_fn:{}:{}", fn_key, mpath),
 			None => 
-			    format!("!__associated_fn:{}:{}", impl_name, item_name),
+			    format!("!__associated_fn:{}:{}", fn_key, item_name),
 		    };
 		    
-		    let c_item = syn::parse2::<ImplItemConst>(quote! {
+		    let registered = registry_entry(&quote! { __hotpatch_internal_pwrap });
+		    let abi = abi_fingerprint(&fargs, &output_type);
+		    let mut c_item = syn::parse2::<ImplItemConst>(quote! {
 			#[cfg(not(doc))]
 			#[allow(non_upper_case_globals)]
-			#vis const #item_name: hotpatch::MutConst<Patchable<dyn Fn#fargs -> #output_type + Send + Sync + 'static>> =hotpatch::MutConst::new(|| {
+			#vis const #patchable_name: hotpatch::MutConst<Patchable<dyn Fn#fargs -> #output_type + Send + Sync + 'static>> =hotpatch::MutConst::new(|| {
 			    #[cfg(not(doc))]
 			    #[allow(non_upper_case_globals)]
 			    static __hotpatch_internal_pwrap: hotpatch::Patchable<
 				    dyn Fn#fargs -> #output_type + Send + Sync + 'static,
 				> = hotpatch::Patchable::__new(|| {
 				    hotpatch::Patchable::__new_internal(
-					Box::new(#self_ty::#fn_name)
+					Box::new(<#self_ty>::#fn_name)
 					    as Box<dyn Fn#fargs -> #output_type + Send + Sync + 'static>,
 					concat!(module_path!(), "::", #mname),
 					#sigtext,
+					#abi,
+					#on_panic,
 				    )
 				});
+			    #registered
 			    &__hotpatch_internal_pwrap
 			});
 		    }).unwrap();
 		    let f_item = syn::parse2::<ImplItemMethod>(quote! {
 			#item
 		    }).unwrap();
-		    (syn::ImplItem::Method(docitem), syn::ImplItem::Const(c_item), syn::ImplItem::Method(f_item))
+
+		    if trait_ident.is_some() {
+			// the forwarding method is the trait method itself, so it
+			// has to exist for docs too and carries the documentation
+			let mut forward_item = forward_item.unwrap();
+			// the body moves out of the trait impl, where `Self::Assoc` needs the trait
+			let mut f_item = f_item;
+			if let Some(trait_path) = &trait_path {
+			    QualifySelf { self_ty: &self_ty, trait_path }.visit_impl_item_method_mut(&mut f_item);
+			}
+			forward_item.attrs = docitem.attrs.into_iter().filter(|a| !is_cfg_doc(a)).collect();
+			// trait methods have no visibility of their own, and the
+			// type's is unknown here, so it's as visible as the type is
+			c_item.vis = syn::parse_quote! { pub };
+			c_item.attrs.retain(|a| !a.path.is_ident("cfg"));
+			inherent_items.push(syn::ImplItem::Const(c_item));
+			inherent_items.push(syn::ImplItem::Method(f_item));
+			return vec![syn::ImplItem::Method(forward_item)];
+		    }
+		    if let Some(f) = &mut forward_item {
+			f.attrs.push(syn::parse_quote! { #[cfg(not(doc))] });
+		    }
+		    let mut items = vec![syn::ImplItem::Method(docitem), syn::ImplItem::Const(c_item)];
+		    items.extend(forward_item.map(syn::ImplItem::Method));
+		    items.push(syn::ImplItem::Method(f_item));
+		    items
                 }
+                // nothing to patch here, so these pass straight through
+                syn::ImplItem::Const(_) | syn::ImplItem::Type(_) => vec![item],
                 _ => panic!("There's something in this impl block I can't hotpatch yet"),
             }
-        }).fold(vec![], |mut acc, (c1, c2, c3)| {acc.push(c1); acc.push(c2); acc.push(c3); acc});
+        }).collect();
+
+    let (impl_generics, _, where_clause) = fn_item.generics.split_for_impl();
+    let inherent_impl = if inherent_items.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            impl #impl_generics #self_ty #where_clause {
+                #(#inherent_items)*
+            }
+        }
+    };
 
     TokenStream::from(quote! {
     #fn_item
+    #inherent_impl
     })
 }
 
 
-pub fn patch(mut fn_item: ItemImpl, modpath: Option<String>) -> TokenStream {
+pub fn patch(mut fn_item: ItemImpl, options: Options) -> TokenStream {
+    if let Some((ident, _)) = options.instantiate.first().and_then(|i| i.first()) {
+        ident.span().unwrap().error("instantiate(..) is only supported on free functions").emit();
+        return TokenStream::new();
+    }
+    if options.wrap {
+        proc_macro2::Span::call_site().unwrap().error("wrap is only supported on free functions").emit();
+        return TokenStream::new();
+    }
+    let modpath = options.modpath;
     
     let mut tt = proc_macro2::TokenStream::new();
     fn_item.self_ty.to_tokens(&mut tt);
     let impl_name = tt.to_string();
     let self_type = fn_item.self_ty.clone();
+    let trait_ident = trait_ident(&fn_item);
+    let trait_path = fn_item.trait_.as_ref().map(|(_, path, _)| path.clone());
+    // exports are keyed the same way as in `patchable`: by type, or by trait and type
+    let (fn_key, self_path) = match (&trait_ident, &fn_item.trait_) {
+	(Some(t), Some((_, trait_path, _))) => (
+	    format!("<{} as {}>", impl_name, t),
+	    quote! { <#self_type as #trait_path> },
+	),
+	_ => (impl_name.clone(), quote! { <#self_type> }),
+    };
     
     let exports: Vec<_> = fn_item
         .items
         .iter_mut()
         .map(|item| {
             match item {
+                syn::ImplItem::Method(m) if m.sig.asyncness.is_some() => {
+                    async_error(m);
+                    quote! {}
+                }
                 syn::ImplItem::Method(m) => {
-                    let (mut fargs, mut output_type, _item, fn_name, sigtext) = gather_info(m.clone());
+                    let (mut fargs, mut output_type, _item, fn_name, sigtext, _) = gather_info(m.clone());
 		    
 		    // transform arguements from Self notation to concrete type (only in inetermediate variables)
 		    if let syn::Type::Tuple(ref mut t) = fargs {
 			for farg in t.elems.iter_mut() {
-			    transform_self(&impl_name, farg);
+			    transform_self(&self_type, trait_path.as_ref(), farg);
 			}
 		    }
 		    // same but for return value
-		    transform_self(&impl_name, &mut output_type);
+		    transform_self(&self_type, trait_path.as_ref(), &mut output_type);
 
-		    let exnum;
-		    {
-			// scope is used so EXPORTNUM is unlocked faster
-			let mut r = EXPORTNUM.write().unwrap();
-			exnum = *r;
-			*r += 1;
-		    }
 		    
                     m.attrs.append(
                         &mut syn::parse2::<syn::ItemStruct>(quote! {
@@ -140,7 +228,7 @@ pub fn patch(mut fn_item: ItemImpl, modpath: Option<String>) -> TokenStream {
 			    /// ---
 			    /// ## Hotpatch
 			    /// This item is a [`#[patch]`](hotpatch::patch). It will silently define a public static
-			    /// symbol `__HOTPATCH_EXPORT_N` for use in shared object files. See the
+			    /// `__HOTPATCH_EXPORT_<hash>`, listed in the library's manifest for use in shared object files. See the
 			    /// [Hotpatch Documentation](hotpatch) for more information.
                             struct Dummy {}
                         })
@@ -153,25 +241,34 @@ pub fn patch(mut fn_item: ItemImpl, modpath: Option<String>) -> TokenStream {
 		    let mname = match &modpath {
 			Some(mpath) =>
 			    quote! {
-				concat!("::!__associated_fn:", #impl_name, ":" #mpath)
+				concat!("::!__associated_fn:", #fn_key, ":", #mpath)
 			    },
 			None => quote! {
-			    concat!(module_path!(), "::!__associated_fn:", #impl_name, ":", stringify!(#fn_name))
+			    concat!(module_path!(), "::!__associated_fn:", #fn_key, ":", stringify!(#fn_name))
 			},
 		    };
-		    let hotpatch_name = Ident::new(&format!("__HOTPATCH_EXPORT_{}", exnum), Span::call_site());
+		    let key = format!("{}:{}", fn_key, modpath.clone().unwrap_or_else(|| fn_name.to_string()));
+		    let hotpatch_name = export_ident(&key, &fn_name, &sigtext);
+		    let (slot, original) = original_slot(&hotpatch_name, &mut m.block, &fargs, &output_type);
+		    let entry = export_entry(&hotpatch_name);
+		    let abi = abi_fingerprint(&fargs, &output_type);
 		    
 		    quote! {
+			#slot
 			#[doc(hidden)]
-			#[no_mangle]
 			pub static #hotpatch_name: hotpatch::HotpatchExport<fn#fargs -> #output_type> =
 			    hotpatch::HotpatchExport::__new(
-				#self_type :: #item_name,
+				#self_path :: #item_name,
 				#mname,
 				#sigtext,
+				|| #abi,
+				false,
+				#original,
 			    );
+			#entry
 		    }
                 }
+                syn::ImplItem::Const(_) | syn::ImplItem::Type(_) => quote! {},
                 _ => panic!("There's something in this impl block I can't hotpatch yet"),
             }
             }).collect();
@@ -182,7 +279,7 @@ pub fn patch(mut fn_item: ItemImpl, modpath: Option<String>) -> TokenStream {
     })
 }
 
-fn gather_info(item: ImplItemMethod) -> (syn::Type, syn::Type, ImplItemMethod, Ident, String) {
+fn gather_info(item: ImplItemMethod) -> (syn::Type, syn::Type, ImplItemMethod, Ident, String, bool) {
     let fn_name = item.sig.ident.clone();
     let output_type = if let Type(_, t) = &item.sig.output {
         *(t.clone())
@@ -196,12 +293,10 @@ fn gather_info(item: ImplItemMethod) -> (syn::Type, syn::Type, ImplItemMethod, I
     let mut ts = proc_macro2::TokenStream::new();
     output_type.to_tokens(&mut ts);
 
-    let mut args = vec![];
-    for i in 0..item.sig.inputs.len() {
-        if let Typed(arg) = &item.sig.inputs[i] {
-            args.push(arg.ty.clone());
-        }
-    }
+    let has_receiver = item.sig.receiver().is_some();
+
+    // receivers are lowered to an explicit first arguement, so `&self` becomes `&Self`
+    let args: Vec<syn::Type> = item.sig.inputs.iter().map(lower_receiver).collect();
 
     let fargs = syn::parse2::<syn::Type>(if args.is_empty() {
         quote! {
@@ -214,39 +309,129 @@ fn gather_info(item: ImplItemMethod) -> (syn::Type, syn::Type, ImplItemMethod, I
     })
 	.unwrap();
 
-    
     let sigtext = format!(
         "fn({}) -> {}",
-        item.sig
-            .inputs
-            .clone()
-            .into_iter()
-            .map(|input| {
-                if let syn::FnArg::Typed(t) = input {
-                    let mut ts = proc_macro2::TokenStream::new();
-                    t.ty.to_tokens(&mut ts);
-                    ts.to_string()
-                } else {
-                    panic!("self parameter is not allowed. Can't hotpatch methods (yet!)")
-                }
+        args.iter()
+            .map(|t| {
+                let mut ts = proc_macro2::TokenStream::new();
+                t.to_tokens(&mut ts);
+                ts.to_string()
             })
             .collect::<Vec<String>>()
-            .join(", "), 
+            .join(", "),
         ts
     );
 
-    (fargs, output_type, item, fn_name, sigtext)
+    (fargs, output_type, item, fn_name, sigtext, has_receiver)
+}
+
+// The trait in `impl Trait for Type` as it's written, if there is one. The whole
+// path is kept so that traits sharing a name in different modules don't collide.
+fn trait_ident(item: &ItemImpl) -> Option<proc_macro2::TokenStream> {
+    item.trait_.as_ref().map(|(_, path, _)| quote! { #path })
 }
 
+fn async_error(item: &ImplItemMethod) {
+    item.sig.asyncness.span().unwrap().error("Can't hotpatch async methods yet")
+	.help("move the body into a free async fn, which can be #[patchable]")
+	.emit();
+}
+
+fn is_cfg_doc(attr: &syn::Attribute) -> bool {
+    attr.path.is_ident("cfg") && attr.tokens.to_string() == "(doc)"
+}
+
+// Turns a method arguement into the type it takes up in a `Fn` signature.
+// Lifetimes on `&'a self` are dropped so the signature stays higher-ranked.
+fn lower_receiver(input: &syn::FnArg) -> syn::Type {
+    match input {
+        Typed(t) => *t.ty.clone(),
+        syn::FnArg::Receiver(r) => {
+            let mutability = &r.mutability;
+            syn::parse2::<syn::Type>(if r.reference.is_some() {
+                quote! { &#mutability Self }
+            } else {
+                quote! { Self }
+            })
+            .unwrap()
+        }
+    }
+}
+
+// Generates the method that keeps `obj.method()` call syntax working. Arguements
+// are renamed so that patterns in the original signature don't need to be bindable.
+fn forwarding_method(item: &ImplItemMethod, name: &Ident, patchable_name: &Ident) -> ImplItemMethod {
+    let mut sig = item.sig.clone();
+    sig.ident = name.clone();
+    let mut call_args = vec![];
+    for (i, input) in sig.inputs.iter_mut().enumerate() {
+        match input {
+            syn::FnArg::Receiver(r) => {
+                if r.reference.is_none() {
+                    r.mutability = None; // `mut self` only matters to the real body
+                }
+                call_args.push(quote! { self });
+            }
+            Typed(t) => {
+                if let syn::Pat::Ident(p) = &*t.pat {
+                    if p.ident == "self" {
+                        call_args.push(quote! { self });
+                        continue;
+                    }
+                }
+                let arg = Ident::new(&format!("__hotpatch_arg_{}", i), Span::call_site());
+                *t.pat = syn::parse2::<syn::Pat>(quote! { #arg }).unwrap();
+                call_args.push(quote! { #arg });
+            }
+        }
+    }
+    let vis = &item.vis;
+    syn::parse2::<ImplItemMethod>(quote! {
+	#[inline(always)]
+	#vis #sig {
+	    (Self::#patchable_name)(#(#call_args),*)
+	}
+    })
+    .unwrap()
+}
+
+// Qualifies every `Self::Assoc` type as `<Type as Trait>::Assoc`
+struct QualifySelf<'a> {
+    self_ty: &'a syn::Type,
+    trait_path: &'a syn::Path,
+}
+
+impl VisitMut for QualifySelf<'_> {
+    fn visit_type_mut(&mut self, ty: &mut syn::Type) {
+        if let syn::Type::Path(p) = ty {
+            if p.qself.is_none() && p.path.segments.len() > 1 && p.path.segments[0].ident == "Self" {
+                transform_self(self.self_ty, Some(self.trait_path), ty);
+            }
+        }
+        syn::visit_mut::visit_type_mut(self, ty);
+    }
+}
+
+// Replaces `Self` with the impl's type. In a trait impl `Self::Assoc` becomes
+// `<Type as Trait>::Assoc`, as `Type::Assoc` would be ambiguous.
 // TODO: is there a crate for this?
-fn transform_self(impl_name: &str, farg: &mut syn::Type) {
+fn transform_self(self_ty: &syn::Type, trait_path: Option<&syn::Path>, farg: &mut syn::Type) {
     use syn::Type::*;
+    if let Path(p) = farg {
+	if p.qself.is_none() && matches!(p.path.segments.first(), Some(s) if s.ident == "Self") {
+	    let rest: Vec<_> = p.path.segments.iter().skip(1).collect();
+	    if rest.is_empty() {
+		*farg = self_ty.clone();
+		return; // already concrete
+	    }
+	    *farg = match trait_path {
+		Some(t) => syn::parse_quote! { <#self_ty as #t>::#(#rest)::* },
+		None => syn::parse_quote! { <#self_ty>::#(#rest)::* },
+	    };
+	}
+    }
     match farg {
 	Path(p) => {
-	    if p.path.segments.first().map(|s| s.ident.to_string()) == Some("Self".to_owned()) {
-		let span = p.path.segments.first().unwrap().ident.span();
-		p.path.segments.first_mut().unwrap().ident = Ident::new(&impl_name, span);
-	    }
 
 	    // generics too
 	    use syn::PathArguments::*;
@@ -256,26 +441,30 @@ fn transform_self(impl_name: &str, farg: &mut syn::Type) {
 			for arg in args.args.iter_mut() {
 			    use syn::GenericArgument::*;
 			    match arg {
-				Type(t) => transform_self(impl_name, t),
-				Binding(b) => transform_self(impl_name, &mut b.ty),
+				Type(t) => transform_self(self_ty, trait_path, t),
+				Binding(b) => transform_self(self_ty, trait_path, &mut b.ty),
 				Constraint(c) => {
 				    c.ident.span().unwrap().error("Can't hotpatch a non-fully-defined function")
 					.help("Trait bounds in functions are not allowed")
 					.help("Patchable items cannot be generic")
 					.emit();
 				},
-				Const(_c) => todo!("The hotpatch dev was lazy and doesn't want to figure out how to do recursive type analysis on const generics. File an issue on the github repo: https://github.com/Shizcow/hotpatch."),
+				Const(c) => {
+				    c.span().unwrap().error("Can't hotpatch an associated function/method with const generic arguements in its signature")
+					.help("Try this as a bare function (not inside an impl) instead")
+					.emit();
+				},
 				Lifetime(_) => (),
 			    }
 			}
 		    },
 		    Parenthesized(p) => {
 			for input in p.inputs.iter_mut() {
-			    transform_self(impl_name, input);
+			    transform_self(self_ty, trait_path, input);
 			}
 			use syn::ReturnType::*;
 			match &mut p.output {
-			    Type(_, t) => transform_self(impl_name, t),
+			    Type(_, t) => transform_self(self_ty, trait_path, t),
 			    Default => (),
 			}
 		    },
@@ -284,18 +473,18 @@ fn transform_self(impl_name: &str, farg: &mut syn::Type) {
 	    }
 	},
 	Reference(r) => {
-	    transform_self(impl_name, &mut r.elem);
+	    transform_self(self_ty, trait_path, &mut r.elem);
 	},
 	Group(g) => {
-	    transform_self(impl_name, &mut g.elem);
+	    transform_self(self_ty, trait_path, &mut g.elem);
 	},
 	BareFn(b) => {
 	    for input in b.inputs.iter_mut() {
-		transform_self(impl_name, &mut input.ty);
+		transform_self(self_ty, trait_path, &mut input.ty);
 	    }
 	    use syn::ReturnType::*;
 	    match &mut b.output {
-		Type(_, t) => transform_self(impl_name, t),
+		Type(_, t) => transform_self(self_ty, trait_path, t),
 		Default => (),
 	    }
 	},
@@ -307,7 +496,7 @@ fn transform_self(impl_name: &str, farg: &mut syn::Type) {
 			qself: None,
 			path: t.path.clone(),
 		    });
-		    transform_self(impl_name, &mut tpath);
+		    transform_self(self_ty, trait_path, &mut tpath);
 		    if let syn::Type::Path(p) = tpath {
 			t.path = p.path;
 		    }
@@ -322,7 +511,7 @@ fn transform_self(impl_name: &str, farg: &mut syn::Type) {
 			qself: None,
 			path: t.path.clone(),
 		    });
-		    transform_self(impl_name, &mut tpath);
+		    transform_self(self_ty, trait_path, &mut tpath);
 		    if let syn::Type::Path(p) = tpath {
 			t.path = p.path;
 		    }
@@ -330,7 +519,7 @@ fn transform_self(impl_name: &str, farg: &mut syn::Type) {
 	    }
 	},
 	Array(a) => {
-	    transform_self(impl_name, &mut a.elem);
+	    transform_self(self_ty, trait_path, &mut a.elem);
 	},
 	Infer(_) => (),
 	Macro(m) => 
@@ -340,17 +529,17 @@ fn transform_self(impl_name: &str, farg: &mut syn::Type) {
 	    .emit(),
 	Never(_) => (),
 	Paren(p) => {
-	    transform_self(impl_name, &mut p.elem);
+	    transform_self(self_ty, trait_path, &mut p.elem);
 	},
 	Ptr(p) => {
-	    transform_self(impl_name, &mut p.elem);
+	    transform_self(self_ty, trait_path, &mut p.elem);
 	},
 	Slice(p) => {
-	    transform_self(impl_name, &mut p.elem);
+	    transform_self(self_ty, trait_path, &mut p.elem);
 	},
 	Tuple(t) => {
 	    for elem in t.elems.iter_mut() {
-		transform_self(impl_name, elem);
+		transform_self(self_ty, trait_path, elem);
 	    }
 	},
 	Verbatim(_) => (), // not found in normal source code
@@ -3,24 +3,39 @@
 //! You probably want documentation for the [`hotpatch`](https://docs.rs/hotpatch) crate.
 
 use proc_macro::TokenStream;
-use std::sync::RwLock;
-use syn::{parse::Nothing, ItemFn, ItemImpl, Path};
+use syn::visit_mut::VisitMut;
+use syn::{DeriveInput, Ident, ItemFn, ItemImpl};
 
+mod abi;
 mod item_fn;
 mod item_impl;
-
-lazy_static::lazy_static! {
-    static ref EXPORTNUM: RwLock<usize> = RwLock::new(0);
-}
+mod options;
+use options::Options;
 
 /// Transforms a function into a [`Patchable`](struct.Patchable.html) capable of having
 /// its behavior redefined at runtime.
 ///
-/// Takes a single optional arguement: `modpath`. Used to spoof the module
+/// Takes an optional arguement: `modpath`. Used to spoof the module
 /// path.
 ///
+/// On an `impl` block, associated functions are replaced by their
+/// [`Patchable`](struct.Patchable.html) under the same name. Methods keep their name, and
+/// theirs is `{method}_patchable`. In `impl Trait for Type` it's
+/// `{trait}_{method}_patchable`, with the trait path in snake case, and is `pub`.
+///
+/// `catch_panic` catches panics in patched definitions at the call boundary and reports
+/// them to the hook set with [`set_panic_hook`](fn.set_panic_hook.html), then lets them
+/// carry on to the caller. `catch_panic(revert)` also puts the default definition back.
+///
+/// Generic functions additionally require `instantiate(..)`, listing the
+/// types to generate a [`Patchable`](struct.Patchable.html) for. Each entry binds
+/// every type parameter, either as `T = u32` or as a group such as `(T = u32, U = bool)`.
+/// The [`Patchable`](struct.Patchable.html)s are placed in a module named after the function.
+///
 /// ## Example
 /// ```
+/// # use hotpatch::*;
+/// # fn main() {}
 /// #[patchable]
 /// fn foo() {}
 ///
@@ -28,17 +43,29 @@ lazy_static::lazy_static! {
 /// fn bar() {
 ///   foo(); // foo is callable, just as a functor
 /// }
+///
+/// #[patchable(instantiate(T = u32, T = String))]
+/// fn parse<T: std::str::FromStr + Default>(s: &str) -> T {
+///   s.parse().unwrap_or_default() // patched with parse::u32 and parse::string
+/// }
+///
+/// #[patchable(catch_panic(revert))] // a patch that panics is replaced by the original
+/// fn baz() {}
 /// ```
 #[proc_macro_attribute]
 pub fn patchable(attr: TokenStream, input: TokenStream) -> TokenStream {
-    let modpath = get_modpath(attr);
-    if modpath.is_err() {
+    let options = match get_options(attr) {
+        Ok(options) => options,
+        Err(()) => return TokenStream::new(),
+    };
+    if let Err(e) = options.check_patchable() {
+        e.span().unwrap().error(e.to_string()).emit();
         return TokenStream::new();
     }
     if let Ok(item) = syn::parse::<ItemFn>(input.clone()) {
-        item_fn::patchable(item, modpath.unwrap())
+        item_fn::patchable(item, options)
     } else if let Ok(item) = syn::parse::<ItemImpl>(input) {
-        item_impl::patchable(item, modpath.unwrap())
+        item_impl::patchable(item, options)
     } else {
         panic!("I can't hotpatch this yet!");
     }
@@ -48,11 +75,30 @@ pub fn patchable(attr: TokenStream, input: TokenStream) -> TokenStream {
 /// being exported and changing the behavior of a function in a seperate binary
 /// at runtime. **The original function is preserved.**
 ///
-/// Takes a single optional arguement: `modpath`. Used to spoof the module
+/// Takes an optional arguement: `modpath`. Used to spoof the module
 /// path.
 ///
+/// On an `impl` block, associated functions are replaced by their
+/// [`Patchable`](struct.Patchable.html) under the same name. Methods keep their name, and
+/// theirs is `{method}_patchable`. In `impl Trait for Type` it's
+/// `{trait}_{method}_patchable`, with the trait path in snake case, and is `pub`.
+///
+/// Generic functions take the same `instantiate(..)` list as
+/// [`#[patchable]`](patchable), exporting each instantiation seperately.
+///
+/// `wrap` exports a wrapper around the definition it replaces, which is passed in as the
+/// first arguement. The rest of the signature has to match the
+/// [`Patchable`](struct.Patchable.html). See [`wrap`](trait.HotpatchWrap.html). Loading the
+/// export again, as when a library is rebuilt, replaces its wrapper wherever it is in the
+/// stack.
+///
+/// The body of a non-generic patch can call the host's default definition with
+/// [`original!()`](macro.original.html), which is resolved when the patch is loaded.
+///
 /// ## Example
 /// ```
+/// # use hotpatch::*;
+/// # fn main() {}
 /// #[patch]
 /// fn foo() {}
 ///
@@ -60,35 +106,317 @@ pub fn patchable(attr: TokenStream, input: TokenStream) -> TokenStream {
 /// fn bar() {
 ///   foo(); // can still call foo
 /// }
+///
+/// #[patch(instantiate(T = u32, T = String))]
+/// fn parse<T: std::str::FromStr + Default>(s: &str) -> T {
+///   T::default()
+/// }
+///
+/// #[patch(mymod::qux)]
+/// fn qux(a: i32) -> i32 {
+///   if a == 0 { 1 } else { original!()(a) } // calls mymod::qux in the host
+/// }
+///
+/// #[patch(wrap)] // patches fn double(a: i32) -> i32
+/// fn double(prev: &dyn Fn(i32) -> i32, a: i32) -> i32 {
+///   prev(a.min(1000))
+/// }
 /// ```
 #[proc_macro_attribute]
 pub fn patch(attr: TokenStream, input: TokenStream) -> TokenStream {
-    let modpath = get_modpath(attr);
-    if modpath.is_err() {
+    let options = match get_options(attr) {
+        Ok(options) => options,
+        Err(()) => return TokenStream::new(),
+    };
+    if let Err(e) = options.check_patch() {
+        e.span().unwrap().error(e.to_string()).emit();
         return TokenStream::new();
     }
     if let Ok(fn_item) = syn::parse::<ItemFn>(input.clone()) {
-        item_fn::patch(fn_item, modpath.unwrap())
+        item_fn::patch(fn_item, options)
     } else if let Ok(item) = syn::parse::<ItemImpl>(input) {
-        item_impl::patch(item, modpath.unwrap())
+        item_impl::patch(item, options)
     } else {
         panic!("I can't turn this into a patch yet!");
     }
 }
 
-fn get_modpath(attr: TokenStream) -> Result<Option<String>, ()> {
-    if syn::parse::<Nothing>(attr.clone()).is_ok() {
-        Ok(None)
-    } else {
-        let s = attr.to_string();
-        let path = syn::parse::<Path>(attr);
-        if path.is_err() {
-            proc_macro::Span::call_site().error("Expected module path")
-		.help("Just use #[patchable]; it's already module aware.")
-		.help("If you're trying to spoof a module path, the supplied arguement is an invalid path")
-		.emit();
-            return Err(());
+/// Implements [`HotpatchAbi`](trait.HotpatchAbi.html), fingerprinting the layout of a
+/// struct, enum or union so that [`#[patchable]`](patchable) can tell when it changed.
+///
+/// Every field type must implement [`HotpatchAbi`](trait.HotpatchAbi.html) as well.
+/// Type parameters are bound by it.
+///
+/// ## Example
+/// ```
+/// # use hotpatch::*;
+/// # fn main() {}
+/// #[derive(HotpatchAbi)]
+/// pub struct Foo {
+///     pub description: &'static str,
+/// }
+///
+/// #[patchable]
+/// fn describe(foo: &Foo) -> String { // rejects libraries built with a different Foo
+///     foo.description.to_owned()
+/// }
+/// ```
+#[proc_macro_derive(HotpatchAbi)]
+pub fn hotpatch_abi(input: TokenStream) -> TokenStream {
+    abi::derive(syn::parse_macro_input!(input as DeriveInput))
+}
+
+fn get_options(attr: TokenStream) -> Result<Options, ()> {
+    syn::parse::<Options>(attr).map_err(|e| {
+        e.span()
+            .unwrap()
+            .error(e.to_string())
+            .help("Just use #[patchable]; it's already module aware.")
+            .help("If you're trying to spoof a module path, the supplied arguement is an invalid path")
+            .help("Generic functions take a list of types, such as instantiate(T = u32, T = String)")
+            .emit();
+    })
+}
+
+// Name of the static holding an export. It's a hash of the crate, the key it's exported
+// under, the item's own name and its signature, so it doesn't depend on expansion order.
+// `module_path!()` isn't known here, but the name only has to be unique within the
+// module the static is declared in, which the item's name ensures even if two items are
+// exported under the same key. The export is found through the manifest by the symbol
+// string it carries, which does include the module path.
+fn export_ident(key: &str, item: &Ident, sigtext: &str) -> Ident {
+    let krate = std::env::var("CARGO_CRATE_NAME")
+        .or_else(|_| std::env::var("CARGO_PKG_NAME"))
+        .unwrap_or_default();
+    // FNV-1a, as std's hashers aren't guaranteed to be stable between releases
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in [krate.as_str(), key, &item.to_string(), sigtext].join("\0").bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    Ident::new(&format!("__HOTPATCH_EXPORT_{:016x}", hash), proc_macro2::Span::call_site())
+}
+
+// Lists an export in the library's manifest, so it can be found without knowing its name
+fn export_entry(hotpatch_name: &Ident) -> proc_macro2::TokenStream {
+    let entry_name = Ident::new(
+        &hotpatch_name.to_string().replace("__HOTPATCH_EXPORT_", "__HOTPATCH_ENTRY_"),
+        proc_macro2::Span::call_site(),
+    );
+    quote::quote! {
+        #[doc(hidden)]
+        #[hotpatch::linkme::distributed_slice(hotpatch::HOTPATCH_EXPORTS)]
+        #[linkme(crate = hotpatch::linkme)]
+        static #entry_name: hotpatch::HotpatchExportEntry = hotpatch::HotpatchExportEntry::__new(&#hotpatch_name);
+    }
+}
+
+// Points `original!()` in a patch body at a slot that the host fills with the default
+// definition when the patch is loaded. Calls are replaced token by token, so that ones
+// inside other macros like `println!` are found too. Returns the slot's static and the
+// arguement for `HotpatchExport::__new`, which is `None` if the body never calls it.
+fn original_slot(
+    hotpatch_name: &Ident,
+    block: &mut syn::Block,
+    fargs: &syn::Type,
+    output_type: &syn::Type,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let slot_name = Ident::new(
+        &hotpatch_name.to_string().replace("__HOTPATCH_EXPORT_", "__HOTPATCH_ORIGINAL_"),
+        proc_macro2::Span::call_site(),
+    );
+    let mut found = false;
+    let tokens = replace_original(
+        quote::ToTokens::to_token_stream(&*block),
+        &quote::quote! { hotpatch::Original::__get(&#slot_name) },
+        &mut found,
+    );
+    if !found {
+        return (quote::quote! {}, quote::quote! { None });
+    }
+    match syn::parse2(tokens) {
+        Ok(b) => *block = b,
+        Err(e) => return (e.to_compile_error(), quote::quote! { None }),
+    }
+    (
+        quote::quote! {
+            #[doc(hidden)]
+            #[allow(non_upper_case_globals)]
+            static #slot_name: hotpatch::Original<dyn Fn #fargs -> #output_type + Send + Sync + 'static> =
+                hotpatch::Original::__new();
+        },
+        quote::quote! { Some(&#slot_name) },
+    )
+}
+
+// Replaces every `original!()`, `hotpatch::original!()` or `::hotpatch::original!()`
+fn replace_original(
+    tokens: proc_macro2::TokenStream,
+    with: &proc_macro2::TokenStream,
+    found: &mut bool,
+) -> proc_macro2::TokenStream {
+    use proc_macro2::{Delimiter, Group, TokenTree};
+    let is_punct = |tt: Option<&TokenTree>, c| matches!(tt, Some(TokenTree::Punct(p)) if p.as_char() == c);
+    let mut out: Vec<TokenTree> = vec![];
+    let mut iter = tokens.into_iter();
+    while let Some(tt) = iter.next() {
+        match tt {
+            TokenTree::Ident(i) if i == "original" => {
+                let mut ahead = iter.clone();
+                let bang = ahead.next();
+                match ahead.next() {
+                    Some(TokenTree::Group(g)) if is_punct(bang.as_ref(), '!') && g.stream().is_empty() => {
+                        iter = ahead;
+                        let n = out.len();
+                        if n >= 3
+                            && matches!(&out[n - 3], TokenTree::Ident(h) if h == "hotpatch")
+                            && is_punct(out.get(n - 2), ':')
+                            && is_punct(out.get(n - 1), ':')
+                        {
+                            out.truncate(n - 3);
+                            let n = out.len();
+                            if n >= 2 && is_punct(out.get(n - 2), ':') && is_punct(out.get(n - 1), ':') {
+                                out.truncate(n - 2);
+                            }
+                        }
+                        let mut call = Group::new(Delimiter::Parenthesis, with.clone());
+                        call.set_span(i.span());
+                        out.push(call.into());
+                        *found = true;
+                    }
+                    _ => out.push(TokenTree::Ident(i)),
+                }
+            }
+            TokenTree::Group(g) => {
+                let mut group = Group::new(g.delimiter(), replace_original(g.stream(), with, found));
+                group.set_span(g.span());
+                out.push(group.into());
+            }
+            tt => out.push(tt),
+        }
+    }
+    out.into_iter().collect()
+}
+
+// Lists a patchable in the process-wide registry. The static is wrapped in an
+// anonymous const so that it doesn't need a name of its own.
+fn registry_entry(patchable: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    quote::quote! {
+        #[cfg(not(doc))]
+        const _: () = {
+            #[hotpatch::linkme::distributed_slice(hotpatch::HOTPATCH_REGISTRY)]
+            #[linkme(crate = hotpatch::linkme)]
+            static ENTRY: hotpatch::RegistryEntry = hotpatch::RegistryEntry::__new(&#patchable);
+        };
+    }
+}
+
+// Layout fingerprint of a signature, see hotpatch::HotpatchAbi. Arguement and return types
+// that don't implement it contribute 0, so the expression always compiles.
+fn abi_fingerprint(fargs: &syn::Type, output_type: &syn::Type) -> proc_macro2::TokenStream {
+    let mut types: Vec<syn::Type> = match fargs {
+        syn::Type::Tuple(t) => t.elems.iter().cloned().collect(),
+        t => vec![t.clone()],
+    };
+    types.push(output_type.clone());
+    // named lifetimes aren't in scope where the probes are, and don't change the layout
+    for t in types.iter_mut() {
+        EraseLifetimes.visit_type_mut(t);
+    }
+    quote::quote! {
+        {
+            #[allow(unused_imports)]
+            use hotpatch::abi::{AbiKnown as _, AbiUnknown as _};
+            hotpatch::abi::signature(&[#((&hotpatch::abi::Probe::<#types>::new()).fingerprint()),*])
+        }
+    }
+}
+
+struct EraseLifetimes;
+
+impl VisitMut for EraseLifetimes {
+    fn visit_lifetime_mut(&mut self, lifetime: &mut syn::Lifetime) {
+        if lifetime.ident != "static" {
+            lifetime.ident = Ident::new("_", lifetime.ident.span());
         }
-        Ok(Some(s.replace(" ", "")))
+    }
+}
+
+// Turns a type or trait into something usable as an identifier, eg `Vec<u8>` to `vec_u8`
+fn snake_case(s: &str) -> String {
+    let mut out = String::new();
+    let mut sep = false;
+    for c in s.chars() {
+        if !(c.is_alphanumeric() || c == '_') {
+            sep = true;
+            continue;
+        }
+        if (sep || c.is_uppercase()) && !out.is_empty() && !out.ends_with('_') {
+            out.push('_');
+        }
+        sep = false;
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replaced(tokens: proc_macro2::TokenStream) -> (String, bool) {
+        let mut found = false;
+        let out = replace_original(tokens, &quote::quote! { ORIG }, &mut found);
+        (out.to_string(), found)
+    }
+
+    #[test]
+    fn replaces_every_path_to_original() {
+        for call in [
+            quote::quote! { original!()(a) },
+            quote::quote! { hotpatch::original!()(a) },
+            quote::quote! { ::hotpatch::original!()(a) },
+        ] {
+            assert_eq!(replaced(call), (quote::quote! { (ORIG)(a) }.to_string(), true));
+        }
+    }
+
+    #[test]
+    fn replaces_inside_groups_and_macros() {
+        let body = quote::quote! {{
+            if a < 0 {
+                return 0;
+            }
+            println!("{}", original!()(a));
+            hotpatch::original!()(a)
+        }};
+        let expected = quote::quote! {{
+            if a < 0 {
+                return 0;
+            }
+            println!("{}", (ORIG)(a));
+            (ORIG)(a)
+        }};
+        assert_eq!(replaced(body), (expected.to_string(), true));
+    }
+
+    #[test]
+    fn leaves_other_uses_of_the_name() {
+        let body = quote::quote! {{
+            let original = 1;
+            other::original!(a);
+            original + original!{x}
+        }};
+        assert_eq!(replaced(body.clone()), (body.to_string(), false));
+    }
+
+    #[test]
+    fn export_idents_are_unique_per_item() {
+        let foo = Ident::new("foo", proc_macro2::Span::call_site());
+        let bar = Ident::new("bar", proc_macro2::Span::call_site());
+        // `#[patch] fn foo()` and `#[patch(foo)] fn bar()` in the same module
+        assert_ne!(export_ident("foo", &foo, "fn() -> ()"), export_ident("foo", &bar, "fn() -> ()"));
+        // stable across expansions
+        assert_eq!(export_ident("foo", &foo, "fn() -> ()"), export_ident("foo", &foo, "fn() -> ()"));
     }
 }
@@ -0,0 +1,124 @@
+use quote::{quote, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::{parenthesized, token, Ident, Path, Token};
+
+/// Arguements accepted by `#[patchable(...)]` and `#[patch(...)]`.
+#[derive(Default)]
+pub struct Options {
+    /// Spoofed module path, such as `mymod::baz`
+    pub modpath: Option<String>,
+    /// Each entry is one monomorphization of a generic function, binding
+    /// every type parameter to a concrete type
+    pub instantiate: Vec<Vec<(Ident, syn::Type)>>,
+    /// `catch_panic` or `catch_panic(revert)`, only for `#[patchable]`
+    pub catch_panic: Option<CatchPanic>,
+    /// `wrap`, only for `#[patch]`: the export takes the previous definition first
+    pub wrap: bool,
+}
+
+#[derive(Clone, Copy)]
+pub enum CatchPanic {
+    Report,
+    Revert,
+}
+
+impl Options {
+    /// The `hotpatch::OnPanic` to construct a `Patchable` with
+    pub fn on_panic(&self) -> proc_macro2::TokenStream {
+        match self.catch_panic {
+            None => quote! { hotpatch::OnPanic::Propagate },
+            Some(CatchPanic::Report) => quote! { hotpatch::OnPanic::Report },
+            Some(CatchPanic::Revert) => quote! { hotpatch::OnPanic::Revert },
+        }
+    }
+    /// Errors for options that only make sense on a `#[patch]`
+    pub fn check_patchable(&self) -> syn::Result<()> {
+        if self.wrap {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "wrap only applies to #[patch]",
+            ));
+        }
+        Ok(())
+    }
+    /// Errors for options that only make sense on a `#[patchable]`
+    pub fn check_patch(&self) -> syn::Result<()> {
+        match self.catch_panic {
+            Some(_) => Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "catch_panic only applies to #[patchable]",
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Parse for Options {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = Options::default();
+        while !input.is_empty() {
+            if input.peek(Ident) && input.peek2(token::Paren) {
+                let ident: Ident = input.parse()?;
+                let content;
+                parenthesized!(content in input);
+                match ident.to_string().as_str() {
+                    "instantiate" => options.instantiate.extend(parse_instances(&content)?),
+                    "catch_panic" => {
+                        let mode: Ident = content.parse()?;
+                        if mode != "revert" {
+                            return Err(syn::Error::new(mode.span(), "Expected catch_panic(revert)"));
+                        }
+                        options.catch_panic = Some(CatchPanic::Revert);
+                    }
+                    _ => return Err(syn::Error::new(ident.span(), "Unknown option")),
+                }
+            } else {
+                let path: Path = input.parse()?;
+                if path.is_ident("catch_panic") {
+                    options.catch_panic = Some(CatchPanic::Report);
+                } else if path.is_ident("wrap") {
+                    options.wrap = true;
+                } else if options.modpath.is_some() {
+                    return Err(syn::Error::new_spanned(path, "Only one module path may be given"));
+                } else {
+                    options.modpath = Some(path.to_token_stream().to_string().replace(" ", ""));
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(options)
+    }
+}
+
+// instantiate(T = u32, T = String) or instantiate((T = u32, U = bool), ..)
+fn parse_instances(input: ParseStream) -> syn::Result<Vec<Vec<(Ident, syn::Type)>>> {
+    let mut instances = vec![];
+    while !input.is_empty() {
+        if input.peek(token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            let mut bindings = vec![];
+            while !content.is_empty() {
+                bindings.push(parse_binding(&content)?);
+                if !content.is_empty() {
+                    content.parse::<Token![,]>()?;
+                }
+            }
+            instances.push(bindings);
+        } else {
+            instances.push(vec![parse_binding(input)?]);
+        }
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+        }
+    }
+    Ok(instances)
+}
+
+fn parse_binding(input: ParseStream) -> syn::Result<(Ident, syn::Type)> {
+    let ident: Ident = input.parse()?;
+    input.parse::<Token![=]>()?;
+    Ok((ident, input.parse()?))
+}